@@ -0,0 +1,389 @@
+use std::{env, fs, path::PathBuf};
+
+use escpos::utils::CashDrawer;
+use serde::{Deserialize, Serialize};
+
+use crate::models::{Command, Commands, CutType, RECEIPT_WIDTH_CHARS};
+
+const CONFIG_FILE: &str = "config.toml";
+const DATA_DIR_ENV: &str = "REIKA_DATA_DIR";
+
+/// The built-in preset: the XP-58IIH style printer this service shipped for.
+pub const STANDARD_PRESET: &str = "Standard";
+const STANDARD_VENDOR_ID: u16 = 0x0483;
+const STANDARD_PRODUCT_ID: u16 = 0x5840;
+/// Picks the first plugged-in printer from `KNOWN_PRINTERS` on startup.
+pub const AUTO_PRESET: &str = "Auto";
+
+/// Thermal printers `Auto` recognises, as (name, VID, PID).
+pub const KNOWN_PRINTERS: &[(&str, u16, u16)] = &[
+    ("Xprinter XP-58IIH", STANDARD_VENDOR_ID, STANDARD_PRODUCT_ID),
+    ("Epson TM series", 0x04B8, 0x0202),
+    ("Epson TM-T20II", 0x04B8, 0x0E15),
+    ("Epson TM-T20III", 0x04B8, 0x0E28),
+    ("Star TSP100", 0x0519, 0x0003),
+    ("POS-58 (Winbond)", 0x0416, 0x5011),
+    ("POS-80 (ICS Advent)", 0x0FE6, 0x811E),
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct AppConfig {
+    pub printer: PrinterConfig,
+    pub server: ServerConfig,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// Origins allowed to call the API from a browser, with scheme, e.g.
+    /// `https://pos.example.com`. Empty allows any origin, which is only
+    /// meant for development. The service's own test page at `/` is always
+    /// allowed.
+    pub allowed_origins: Vec<String>,
+    /// Largest request body accepted, in bytes. Bigger ones get a 413 before
+    /// anything is buffered.
+    pub max_body_bytes: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            allowed_origins: Vec::new(),
+            max_body_bytes: 4 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Connection {
+    Usb,
+    Serial,
+    /// An installed Windows printer, written to through the spooler.
+    Spooler,
+    /// No printer: the bytes are appended to `capture_path`.
+    File,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum SerialFlowControl {
+    None,
+    Software,
+    Hardware,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NamedPreset {
+    pub name: String,
+    pub vendor_id: u16,
+    pub product_id: u16,
+}
+
+/// A named job staff can print with one click from the test page. The rest
+/// of the entry is a `/print` body.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QuickAction {
+    pub name: String,
+    #[serde(flatten)]
+    pub job: Commands,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PrinterConfig {
+    /// `usb` uses the preset's VID/PID, `serial` uses the `serial_*` fields,
+    /// `spooler` (Windows only) prints to `spooler_printer`, `file` writes
+    /// to `capture_path`.
+    pub connection: Connection,
+    /// Port name, e.g. `COM3` or `/dev/ttyUSB0`.
+    pub serial_port: String,
+    pub baud_rate: u32,
+    pub flow_control: SerialFlowControl,
+    /// Name of the Windows printer as shown in Devices and Printers. It should
+    /// be a Generic / Text Only queue so the raw ESC/POS bytes pass through.
+    pub spooler_printer: String,
+    /// File the `file` connection appends every job's bytes to.
+    pub capture_path: String,
+    /// Empties `capture_path` when a job starts, so it only ever holds the
+    /// last job.
+    pub capture_truncate_per_job: bool,
+    /// `Standard`, `Auto`, or the name of one of `custom_presets`.
+    pub preset: String,
+    pub custom_presets: Vec<NamedPreset>,
+    /// Kicks this drawer pin once a `/print` job has fully printed and cut.
+    /// A failed print never opens the drawer, nor does a spooled one.
+    pub open_drawer_after_print: Option<CashDrawer>,
+    /// Tries at opening the printer on startup before the service starts
+    /// offline and leaves finding it to the health poller. 0 waits for the
+    /// printer forever.
+    pub connect_attempts: u32,
+    /// Wait between attempts at opening the printer, on startup and when
+    /// reconnecting.
+    pub retry_delay_ms: u64,
+    /// Doubles the wait after every failed attempt, up to `retry_max_delay_ms`,
+    /// so a quick blip reconnects fast without flooding the log during a
+    /// long outage.
+    pub retry_backoff: bool,
+    pub retry_max_delay_ms: u64,
+    /// Seconds a `/print` job may take, waiting on reconnects included,
+    /// before it fails with a 504. 0 lets a job wait for the printer forever.
+    pub job_timeout_secs: u64,
+    /// Pin fired by `POST /drawer`.
+    pub drawer_pin: CashDrawer,
+    /// Second printer that `fallback_on_failure` sends jobs to, as a
+    /// `[printer.backup]` table. `None` means there is none.
+    pub backup: Option<BackupPrinterConfig>,
+    /// Tries a `/print` job once on `backup` when it fails or times out on
+    /// the main printer, so it isn't lost.
+    pub fallback_on_failure: bool,
+    /// Sends an init (ESC @) before every job. Turn this off only for clients
+    /// that manage printer state themselves: nothing is reset between jobs, so
+    /// bold, size, justification and code page carry over from the previous
+    /// job until the client sends its own `Init`.
+    pub auto_init_per_job: bool,
+    /// Runs the `/validate` checks on every `/print` body and refuses it with
+    /// a 400 before anything is sent when they fail.
+    pub validate_before_print: bool,
+    /// Font A characters per line: 32 for 58mm paper, 48 for 80mm. Drives
+    /// `Row`, `SectionHeader` and the preview width.
+    pub paper_width_chars: usize,
+    /// Blank lines fed before the cut that ends every job, for cutters that
+    /// sit close enough to shear the last printed row.
+    pub cut_feed_lines: u8,
+    /// Printed before every job, e.g. the store name, address and tax id.
+    /// Same format as the `commands` of a `/print` body.
+    pub header_commands: Vec<Command>,
+    /// Printed after every job, before the automatic cut.
+    pub footer_commands: Vec<Command>,
+    /// Jobs offered as buttons on the test page and at `/actions`.
+    pub quick_actions: Vec<QuickAction>,
+    /// Drains the connection after every job's final cut. Only needed for
+    /// adapters that keep the last bytes buffered, so cuts land a job late.
+    pub flush_after_cut: bool,
+    /// Cut that ends a job whose commands don't already end with one:
+    /// `Full`, `Partial` or `None`.
+    pub default_cut: CutType,
+    /// When the printer is unplugged, `/print` stores the job on disk and
+    /// answers 202 instead of waiting for it to come back. Spooled jobs print
+    /// in order once it reconnects, and new jobs queue behind them until the
+    /// spool is empty.
+    pub spool_when_offline: bool,
+    /// Jobs the spool holds before `/print` starts refusing with a 503.
+    pub spool_max_jobs: usize,
+    /// Seconds between background presence checks that keep `/health` and
+    /// `/events` current between prints. The check enumerates ports and asks
+    /// for the paper and cover sensors; it never prints, feeds or cuts. 0
+    /// turns the poller off, and status polls then probe the printer live.
+    pub health_check_interval_secs: u64,
+    /// Seconds between status requests sent while idle, for USB adapters that
+    /// go to sleep and make the next print reconnect. 0 turns it off.
+    pub keepalive_interval_secs: u64,
+    /// Prints a single test line when the service starts with the printer
+    /// connected, so unattended installs show they work. Skipped if the
+    /// printer is offline at boot.
+    pub self_test_on_startup: bool,
+    /// The only directory `ImageFile` may read from. Empty disables file
+    /// images entirely, since the request body picks the path.
+    pub image_dir: String,
+}
+
+impl Default for PrinterConfig {
+    fn default() -> PrinterConfig {
+        PrinterConfig {
+            connection: Connection::Usb,
+            serial_port: String::new(),
+            baud_rate: 9600,
+            flow_control: SerialFlowControl::None,
+            spooler_printer: String::new(),
+            capture_path: String::new(),
+            capture_truncate_per_job: false,
+            preset: STANDARD_PRESET.to_string(),
+            custom_presets: Vec::new(),
+            open_drawer_after_print: None,
+            drawer_pin: CashDrawer::Pin2,
+            backup: None,
+            fallback_on_failure: false,
+            connect_attempts: 0,
+            retry_delay_ms: 5000,
+            retry_backoff: false,
+            retry_max_delay_ms: 10_000,
+            job_timeout_secs: 30,
+            auto_init_per_job: true,
+            validate_before_print: false,
+            paper_width_chars: RECEIPT_WIDTH_CHARS,
+            cut_feed_lines: 0,
+            default_cut: CutType::Full,
+            flush_after_cut: false,
+            header_commands: Vec::new(),
+            footer_commands: Vec::new(),
+            quick_actions: Vec::new(),
+            spool_when_offline: false,
+            spool_max_jobs: 100,
+            health_check_interval_secs: 10,
+            keepalive_interval_secs: 0,
+            self_test_on_startup: false,
+            image_dir: String::new(),
+        }
+    }
+}
+
+/// How to reach the backup printer, in the same fields `[printer]` uses for
+/// the main one. Everything else, the layout included, is the main printer's.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct BackupPrinterConfig {
+    pub connection: Connection,
+    /// `Standard` or the name of one of the main config's `custom_presets`.
+    pub preset: String,
+    pub serial_port: String,
+    pub baud_rate: u32,
+    pub flow_control: SerialFlowControl,
+    pub spooler_printer: String,
+    pub capture_path: String,
+    pub capture_truncate_per_job: bool,
+}
+
+impl Default for BackupPrinterConfig {
+    fn default() -> BackupPrinterConfig {
+        let main = PrinterConfig::default();
+        BackupPrinterConfig {
+            connection: main.connection,
+            preset: main.preset,
+            serial_port: main.serial_port,
+            baud_rate: main.baud_rate,
+            flow_control: main.flow_control,
+            spooler_printer: main.spooler_printer,
+            capture_path: main.capture_path,
+            capture_truncate_per_job: main.capture_truncate_per_job,
+        }
+    }
+}
+
+impl PrinterConfig {
+    /// Turns the `Auto` preset into a concrete one by looking for a known
+    /// printer on the bus. Falls back to `Standard` when none is plugged in.
+    pub fn resolve_auto_preset(&mut self) {
+        if self.preset != AUTO_PRESET {
+            return;
+        }
+        match crate::usb::detect_known_printer() {
+            Some(found) => {
+                println!(
+                    "Auto-detected {} ({:04x}:{:04x}).",
+                    found.name, found.vendor_id, found.product_id
+                );
+                self.preset = found.name.clone();
+                if self.selected_preset().is_none() {
+                    self.custom_presets.push(found);
+                }
+            }
+            None => {
+                println!("No known printer found for the Auto preset, using {}.", STANDARD_PRESET);
+                self.preset = STANDARD_PRESET.to_string();
+            }
+        }
+    }
+
+    /// The custom preset named by `preset`, or `None` for the built-in one.
+    /// An unknown name also resolves to the built-in preset.
+    pub fn selected_preset(&self) -> Option<&NamedPreset> {
+        self.custom_presets.iter().find(|p| p.name == self.preset)
+    }
+
+    pub fn resolved_vendor_id(&self) -> u16 {
+        self.selected_preset().map_or(STANDARD_VENDOR_ID, |p| p.vendor_id)
+    }
+
+    pub fn resolved_product_id(&self) -> u16 {
+        self.selected_preset().map_or(STANDARD_PRODUCT_ID, |p| p.product_id)
+    }
+
+    /// This config with the connection swapped for the backup's, or `None`
+    /// when no backup is set up. The backup is opened and printed to with it
+    /// exactly like the main printer is with `self`.
+    pub fn backup_config(&self) -> Option<PrinterConfig> {
+        let backup = self.backup.clone()?;
+        Some(PrinterConfig {
+            connection: backup.connection,
+            preset: backup.preset,
+            serial_port: backup.serial_port,
+            baud_rate: backup.baud_rate,
+            flow_control: backup.flow_control,
+            spooler_printer: backup.spooler_printer,
+            capture_path: backup.capture_path,
+            capture_truncate_per_job: backup.capture_truncate_per_job,
+            backup: None,
+            ..self.clone()
+        })
+    }
+}
+
+impl AppConfig {
+    /// Reads `config.toml` from the data directory. A missing or broken file
+    /// falls back to the defaults so the service still starts.
+    pub fn load() -> AppConfig {
+        let path = AppConfig::config_path();
+        match fs::read_to_string(&path) {
+            Ok(contents) => match toml::from_str(&contents) {
+                Ok(config) => config,
+                Err(e) => {
+                    println!("Failed to parse {}: {}. Using the defaults.", path.display(), e);
+                    AppConfig::default()
+                }
+            },
+            Err(_) => {
+                println!("No config found at {}. Using the defaults.", path.display());
+                AppConfig::default()
+            }
+        }
+    }
+
+    pub fn config_path() -> PathBuf {
+        data_dir().join(CONFIG_FILE)
+    }
+}
+
+/// Where the service keeps its files. `REIKA_DATA_DIR` overrides it so several
+/// instances on one machine don't share a config; otherwise it is the
+/// executable's directory.
+pub fn data_dir() -> PathBuf {
+    if let Some(dir) = env::var_os(DATA_DIR_ENV).filter(|dir| !dir.is_empty()) {
+        return PathBuf::from(dir);
+    }
+    env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(|dir| dir.to_path_buf()))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Held by every test that sets environment variables, since the
+    /// environment is shared by the whole test binary.
+    pub(crate) static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn data_dir_env_redirects_the_config_path() {
+        let _env = ENV_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let dir = env::temp_dir().join("reika-instance");
+        env::set_var(DATA_DIR_ENV, &dir);
+        let redirected = AppConfig::config_path();
+        env::remove_var(DATA_DIR_ENV);
+        assert_eq!(redirected, dir.join(CONFIG_FILE));
+    }
+
+    #[test]
+    fn empty_data_dir_env_falls_back_to_the_executable_directory() {
+        let _env = ENV_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        env::set_var(DATA_DIR_ENV, "");
+        let path = AppConfig::config_path();
+        env::remove_var(DATA_DIR_ENV);
+        let exe_dir = env::current_exe().unwrap().parent().unwrap().to_path_buf();
+        assert_eq!(path, exe_dir.join(CONFIG_FILE));
+    }
+}
@@ -0,0 +1,366 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Write},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use escpos::{
+    driver::{Driver, UsbDriver},
+    errors::PrinterError,
+};
+use serialport::{FlowControl, SerialPort};
+#[cfg(windows)]
+use windows_sys::Win32::Graphics::Printing::{
+    ClosePrinter, EndDocPrinter, EndPagePrinter, OpenPrinterW, StartDocPrinterW, StartPagePrinter, WritePrinter,
+    DOC_INFO_1W, PRINTER_HANDLE,
+};
+
+use crate::config::{Connection, PrinterConfig, SerialFlowControl};
+
+const SERIAL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The transport a job is printed over. The command pipeline only sees the
+/// escpos `Driver` trait, so adding a backend means adding a variant here.
+#[derive(Clone)]
+pub enum PrinterDriver {
+    Usb(UsbDriver),
+    Serial(SerialDriver),
+    #[cfg(windows)]
+    Spooler(SpoolerDriver),
+    DryRun(DryRunDriver),
+    File(FileDriver),
+}
+
+impl PrinterDriver {
+    /// Opens whichever connection the config selects. One attempt; the
+    /// retry loop lives in `print::initialize_device`.
+    pub fn open(config: &PrinterConfig) -> Result<PrinterDriver, PrinterError> {
+        match config.connection {
+            Connection::Usb => {
+                let driver = UsbDriver::open(config.resolved_vendor_id(), config.resolved_product_id(), None)?;
+                Ok(PrinterDriver::Usb(driver))
+            }
+            Connection::Serial => {
+                let driver = SerialDriver::open(&config.serial_port, config.baud_rate, config.flow_control)?;
+                Ok(PrinterDriver::Serial(driver))
+            }
+            #[cfg(windows)]
+            Connection::Spooler => {
+                let driver = SpoolerDriver::open(&config.spooler_printer)?;
+                Ok(PrinterDriver::Spooler(driver))
+            }
+            #[cfg(not(windows))]
+            Connection::Spooler => Err(PrinterError::Io("the spooler connection is only available on Windows".to_string())),
+            Connection::File => {
+                let driver = FileDriver::open(&config.capture_path, config.capture_truncate_per_job)?;
+                Ok(PrinterDriver::File(driver))
+            }
+        }
+    }
+
+    /// Called before a job's first byte. Only the file capture cares, to
+    /// start each job on an empty file when asked to.
+    pub fn start_job(&self) -> Result<(), PrinterError> {
+        match self {
+            PrinterDriver::File(driver) => driver.start_job(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether a status request (`DLE EOT`) can go over this connection. The
+    /// spooler would print each one as its own document, and nothing on the
+    /// other side of it could answer anyway.
+    pub fn answers_status(&self) -> bool {
+        matches!(self, PrinterDriver::Usb(_) | PrinterDriver::Serial(_))
+    }
+
+    /// Pushes out anything still buffered between the service and the print
+    /// head. Some USB-serial bridges hold the tail of a job, cut included,
+    /// until the next one arrives; a zero-length bulk write makes them commit.
+    pub fn drain(&self) -> Result<(), PrinterError> {
+        if let PrinterDriver::Usb(driver) = self {
+            driver.write(&[])?;
+        }
+        self.flush()
+    }
+}
+
+impl Driver for PrinterDriver {
+    fn name(&self) -> String {
+        match self {
+            PrinterDriver::Usb(driver) => driver.name(),
+            PrinterDriver::Serial(driver) => driver.name(),
+            #[cfg(windows)]
+            PrinterDriver::Spooler(driver) => driver.name(),
+            PrinterDriver::DryRun(driver) => driver.name(),
+            PrinterDriver::File(driver) => driver.name(),
+        }
+    }
+
+    fn write(&self, data: &[u8]) -> Result<(), PrinterError> {
+        match self {
+            PrinterDriver::Usb(driver) => driver.write(data),
+            PrinterDriver::Serial(driver) => driver.write(data),
+            #[cfg(windows)]
+            PrinterDriver::Spooler(driver) => driver.write(data),
+            PrinterDriver::DryRun(driver) => driver.write(data),
+            PrinterDriver::File(driver) => driver.write(data),
+        }
+    }
+
+    fn read(&self, buf: &mut [u8]) -> Result<usize, PrinterError> {
+        match self {
+            PrinterDriver::Usb(driver) => driver.read(buf),
+            PrinterDriver::Serial(driver) => driver.read(buf),
+            #[cfg(windows)]
+            PrinterDriver::Spooler(driver) => driver.read(buf),
+            PrinterDriver::DryRun(driver) => driver.read(buf),
+            PrinterDriver::File(driver) => driver.read(buf),
+        }
+    }
+
+    fn flush(&self) -> Result<(), PrinterError> {
+        match self {
+            PrinterDriver::Usb(driver) => driver.flush(),
+            PrinterDriver::Serial(driver) => driver.flush(),
+            #[cfg(windows)]
+            PrinterDriver::Spooler(driver) => driver.flush(),
+            PrinterDriver::DryRun(driver) => driver.flush(),
+            PrinterDriver::File(driver) => driver.flush(),
+        }
+    }
+}
+
+/// RS-232 or virtual COM port printer.
+#[derive(Clone)]
+pub struct SerialDriver {
+    port_name: String,
+    port: Arc<Mutex<Box<dyn SerialPort>>>,
+}
+
+impl SerialDriver {
+    pub fn open(port_name: &str, baud_rate: u32, flow_control: SerialFlowControl) -> Result<SerialDriver, PrinterError> {
+        let flow_control = match flow_control {
+            SerialFlowControl::None => FlowControl::None,
+            SerialFlowControl::Software => FlowControl::Software,
+            SerialFlowControl::Hardware => FlowControl::Hardware,
+        };
+        let port = serialport::new(port_name, baud_rate)
+            .flow_control(flow_control)
+            .timeout(SERIAL_TIMEOUT)
+            .open()
+            .map_err(|e| PrinterError::Io(e.to_string()))?;
+        Ok(SerialDriver {
+            port_name: port_name.to_string(),
+            port: Arc::new(Mutex::new(port)),
+        })
+    }
+
+    fn port(&self) -> Result<std::sync::MutexGuard<'_, Box<dyn SerialPort>>, PrinterError> {
+        self.port.lock().map_err(|e| PrinterError::Io(e.to_string()))
+    }
+}
+
+impl Driver for SerialDriver {
+    fn name(&self) -> String {
+        format!("Serial ({})", self.port_name)
+    }
+
+    fn write(&self, data: &[u8]) -> Result<(), PrinterError> {
+        self.port()?.write_all(data).map_err(|e| PrinterError::Io(e.to_string()))
+    }
+
+    fn read(&self, buf: &mut [u8]) -> Result<usize, PrinterError> {
+        self.port()?.read(buf).map_err(|e| PrinterError::Io(e.to_string()))
+    }
+
+    fn flush(&self) -> Result<(), PrinterError> {
+        self.port()?.flush().map_err(|e| PrinterError::Io(e.to_string()))
+    }
+}
+
+/// Captures everything a job would send instead of printing it, for
+/// reproducing layout issues without hardware or paper.
+#[derive(Clone, Default)]
+pub struct DryRunDriver {
+    buffer: Arc<Mutex<Vec<u8>>>,
+}
+
+impl DryRunDriver {
+    pub fn captured(&self) -> Vec<u8> {
+        self.buffer.lock().map(|buffer| buffer.clone()).unwrap_or_default()
+    }
+}
+
+pub fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+impl Driver for DryRunDriver {
+    fn name(&self) -> String {
+        "Dry run".to_string()
+    }
+
+    fn write(&self, data: &[u8]) -> Result<(), PrinterError> {
+        println!("[dry run] {}", to_hex(data));
+        self.buffer
+            .lock()
+            .map_err(|e| PrinterError::Io(e.to_string()))?
+            .extend_from_slice(data);
+        Ok(())
+    }
+
+    fn read(&self, _buf: &mut [u8]) -> Result<usize, PrinterError> {
+        Ok(0)
+    }
+
+    fn flush(&self) -> Result<(), PrinterError> {
+        Ok(())
+    }
+}
+
+/// Appends everything a job sends to a file, for CI and demos without a
+/// printer. The file gets exactly the bytes a printer would.
+#[derive(Clone)]
+pub struct FileDriver {
+    path: String,
+    file: Arc<Mutex<File>>,
+    truncate_per_job: bool,
+}
+
+impl FileDriver {
+    pub fn open(path: &str, truncate_per_job: bool) -> Result<FileDriver, PrinterError> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| PrinterError::Io(format!("failed to open capture file {:?}: {}", path, e)))?;
+        Ok(FileDriver {
+            path: path.to_string(),
+            file: Arc::new(Mutex::new(file)),
+            truncate_per_job,
+        })
+    }
+
+    fn file(&self) -> Result<std::sync::MutexGuard<'_, File>, PrinterError> {
+        self.file.lock().map_err(|e| PrinterError::Io(e.to_string()))
+    }
+
+    fn start_job(&self) -> Result<(), PrinterError> {
+        if self.truncate_per_job {
+            self.file()?.set_len(0).map_err(|e| PrinterError::Io(e.to_string()))?;
+        }
+        Ok(())
+    }
+}
+
+impl Driver for FileDriver {
+    fn name(&self) -> String {
+        format!("File ({})", self.path)
+    }
+
+    fn write(&self, data: &[u8]) -> Result<(), PrinterError> {
+        self.file()?.write_all(data).map_err(|e| PrinterError::Io(e.to_string()))
+    }
+
+    /// A file never answers status queries.
+    fn read(&self, _buf: &mut [u8]) -> Result<usize, PrinterError> {
+        Ok(0)
+    }
+
+    fn flush(&self) -> Result<(), PrinterError> {
+        self.file()?.flush().map_err(|e| PrinterError::Io(e.to_string()))
+    }
+}
+
+/// Null-terminated UTF-16, as the wide Win32 calls expect.
+#[cfg(windows)]
+fn wide(text: &str) -> Vec<u16> {
+    text.encode_utf16().chain(std::iter::once(0)).collect()
+}
+
+/// A printer installed in Windows, for sites where policy won't let the
+/// service claim the USB interface itself. Every write is sent as one RAW
+/// spooler document, so the bytes reach the printer untouched.
+#[cfg(windows)]
+#[derive(Clone)]
+pub struct SpoolerDriver {
+    printer_name: String,
+}
+
+#[cfg(windows)]
+impl SpoolerDriver {
+    /// Checks the printer exists by opening it once. Nothing is sent.
+    pub fn open(printer_name: &str) -> Result<SpoolerDriver, PrinterError> {
+        let driver = SpoolerDriver {
+            printer_name: printer_name.to_string(),
+        };
+        let handle = driver.open_printer()?;
+        unsafe { ClosePrinter(handle) };
+        Ok(driver)
+    }
+
+    fn open_printer(&self) -> Result<PRINTER_HANDLE, PrinterError> {
+        let name = wide(&self.printer_name);
+        let mut handle: PRINTER_HANDLE = 0;
+        if unsafe { OpenPrinterW(name.as_ptr(), &mut handle, std::ptr::null()) } == 0 {
+            return Err(PrinterError::Io(format!(
+                "failed to open printer {:?}: {}",
+                self.printer_name,
+                std::io::Error::last_os_error()
+            )));
+        }
+        Ok(handle)
+    }
+}
+
+/// Writes `data` as a single RAW document on an open printer handle.
+#[cfg(windows)]
+fn write_raw_document(handle: PRINTER_HANDLE, data: &[u8]) -> Result<(), PrinterError> {
+    let mut doc_name = wide("REIKA receipt");
+    let mut datatype = wide("RAW");
+    let doc_info = DOC_INFO_1W {
+        pDocName: doc_name.as_mut_ptr(),
+        pOutputFile: std::ptr::null_mut(),
+        pDatatype: datatype.as_mut_ptr(),
+    };
+    unsafe {
+        if StartDocPrinterW(handle, 1, &doc_info) == 0 {
+            return Err(PrinterError::Io(format!("StartDocPrinter failed: {}", std::io::Error::last_os_error())));
+        }
+        let mut written: u32 = 0;
+        let ok = StartPagePrinter(handle) != 0
+            && WritePrinter(handle, data.as_ptr().cast(), data.len() as u32, &mut written) != 0;
+        let error = std::io::Error::last_os_error();
+        EndPagePrinter(handle);
+        EndDocPrinter(handle);
+        if !ok || written as usize != data.len() {
+            return Err(PrinterError::Io(format!("WritePrinter sent {} of {} bytes: {}", written, data.len(), error)));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+impl Driver for SpoolerDriver {
+    fn name(&self) -> String {
+        format!("Spooler ({})", self.printer_name)
+    }
+
+    fn write(&self, data: &[u8]) -> Result<(), PrinterError> {
+        let handle = self.open_printer()?;
+        let result = write_raw_document(handle, data);
+        unsafe { ClosePrinter(handle) };
+        result
+    }
+
+    /// The spooler is write-only, so status queries get no answer.
+    fn read(&self, _buf: &mut [u8]) -> Result<usize, PrinterError> {
+        Ok(0)
+    }
+
+    fn flush(&self) -> Result<(), PrinterError> {
+        Ok(())
+    }
+}
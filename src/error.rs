@@ -0,0 +1,76 @@
+use std::{fmt, time::Duration};
+
+use escpos::errors::PrinterError;
+use warp::http::StatusCode;
+
+pub const MAINTENANCE_MESSAGE: &str = "MAINTENANCE: The printer is in maintenance mode.";
+/// Starts the `Input` error of a body that isn't valid JSON for a job, so
+/// the response can say just that and leave serde's details to the log.
+pub const JSON_ERROR_MESSAGE: &str = "Failed to parse the JSON.";
+
+/// Why a print request failed, as far as the HTTP layer cares. Printer
+/// errors keep their escpos kind so the status code can follow it.
+#[derive(Debug)]
+pub enum AppError {
+    Printer(PrinterError),
+    /// The job, retries and reconnects included, ran past `job_timeout_secs`.
+    Timeout(Duration),
+    /// Turned away because maintenance mode is on.
+    Maintenance,
+}
+
+impl AppError {
+    /// Bad input is the caller's to fix. Anything the printer did is worth
+    /// retrying once it is back, so it reads as the service being unavailable.
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            AppError::Printer(PrinterError::Input(_)) => StatusCode::BAD_REQUEST,
+            AppError::Printer(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            AppError::Maintenance => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    /// Stable name for clients to branch on; the message may change.
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::Printer(PrinterError::Input(_)) => "INVALID_INPUT",
+            AppError::Printer(_) => "PRINTER_UNAVAILABLE",
+            AppError::Timeout(_) => "TIMEOUT",
+            AppError::Maintenance => "MAINTENANCE",
+        }
+    }
+
+    pub fn retryable(&self) -> bool {
+        !matches!(self, AppError::Printer(PrinterError::Input(_)))
+    }
+
+    /// Short message for the response body. Bad input says what was wrong
+    /// with it, since that is the caller's to fix.
+    pub fn message(&self) -> String {
+        match self {
+            AppError::Printer(PrinterError::Input(e)) if e.starts_with(JSON_ERROR_MESSAGE) => JSON_ERROR_MESSAGE.to_string(),
+            AppError::Printer(PrinterError::Input(e)) => e.clone(),
+            AppError::Printer(PrinterError::InvalidResponse(_)) => "Failed to print: Invalid Response.".to_string(),
+            AppError::Printer(PrinterError::Io(_)) => "Failed to print: IO Error".to_string(),
+            AppError::Timeout(_) => "Failed to print: the job timed out.".to_string(),
+            AppError::Maintenance => MAINTENANCE_MESSAGE.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::Printer(e) => write!(f, "{}", e),
+            AppError::Timeout(limit) => write!(f, "the print job timed out after {:?}", limit),
+            AppError::Maintenance => write!(f, "maintenance mode is on"),
+        }
+    }
+}
+
+impl From<PrinterError> for AppError {
+    fn from(e: PrinterError) -> AppError {
+        AppError::Printer(e)
+    }
+}
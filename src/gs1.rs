@@ -0,0 +1,233 @@
+/// How much data an application identifier takes.
+#[derive(Clone, Copy)]
+enum AiLength {
+    Fixed(usize),
+    Variable(usize),
+}
+
+#[derive(Clone, Copy)]
+struct AiRule {
+    length: AiLength,
+    numeric: bool,
+}
+
+/// The AIs labels commonly carry. Measures (31nn-36nn) and amounts (390n,
+/// 392n) are four digits, the last one placing the decimal point.
+fn ai_rule(ai: &str) -> Option<AiRule> {
+    use AiLength::{Fixed, Variable};
+    let (length, numeric) = match ai {
+        "00" => (Fixed(18), true),
+        "01" | "02" => (Fixed(14), true),
+        "10" | "21" | "22" => (Variable(20), false),
+        "11" | "12" | "13" | "15" | "16" | "17" => (Fixed(6), true),
+        "20" => (Fixed(2), true),
+        "240" | "241" | "400" | "401" | "403" => (Variable(30), false),
+        "30" | "37" => (Variable(8), true),
+        "402" => (Fixed(17), true),
+        "410" | "411" | "412" | "413" | "414" | "415" | "416" | "417" => (Fixed(13), true),
+        "420" => (Variable(20), false),
+        "421" => (Variable(12), false),
+        "422" | "424" | "426" => (Fixed(3), true),
+        _ => match ai.as_bytes() {
+            [b'3', b'1'..=b'6', b'0'..=b'9', b'0'..=b'9'] => (Fixed(6), true),
+            [b'3', b'9', b'0' | b'2', b'0'..=b'9'] => (Variable(15), true),
+            _ => return None,
+        },
+    };
+    Some(AiRule { length, numeric })
+}
+
+/// The characters GS1 allows in element data, minus the parentheses that
+/// delimit AIs in the input.
+fn is_gs1_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!\"%&'*+,-./:;<=>?_".contains(c)
+}
+
+pub struct Element {
+    pub ai: String,
+    pub data: String,
+}
+
+/// Whether `ai` is in the GS1 table of predefined lengths, the only AIs that
+/// may be followed by another without an FNC1 in between. Some fixed-length
+/// AIs, such as 402 and 422, are not in it and still need the separator.
+fn predefined_length(ai: &str) -> bool {
+    matches!(
+        ai.get(..2),
+        Some("00" | "01" | "02" | "03" | "04" | "11" | "12" | "13" | "14" | "15" | "16" | "17" | "18" | "19" | "20" | "31" | "32" | "33" | "34" | "35" | "36" | "41")
+    )
+}
+
+/// Splits `(01)09501101020917(10)ABC` into its elements, checking every AI is
+/// known and its data has the right length and characters.
+pub fn parse(text: &str) -> Result<Vec<Element>, String> {
+    if text.is_empty() {
+        return Err("GS1-128 data must not be empty".to_string());
+    }
+    let mut elements = Vec::new();
+    let mut rest = text;
+    while !rest.is_empty() {
+        let after_open = rest
+            .strip_prefix('(')
+            .ok_or_else(|| format!("GS1-128 expected an AI in parentheses before {:?}", rest))?;
+        let (ai, after_ai) = after_open
+            .split_once(')')
+            .ok_or_else(|| "GS1-128 has an unclosed '('".to_string())?;
+        let (data, next) = after_ai.split_at(after_ai.find('(').unwrap_or(after_ai.len()));
+        let rule = ai_rule(ai).ok_or_else(|| format!("GS1-128 AI ({}) is not supported", ai))?;
+        match rule.length {
+            AiLength::Fixed(length) if data.len() != length => {
+                return Err(format!("GS1-128 AI ({}) takes exactly {} characters", ai, length));
+            }
+            AiLength::Variable(max) if data.is_empty() || data.len() > max => {
+                return Err(format!("GS1-128 AI ({}) takes 1 to {} characters", ai, max));
+            }
+            _ => {}
+        }
+        if rule.numeric && !data.chars().all(|c| c.is_ascii_digit()) {
+            return Err(format!("GS1-128 AI ({}) data must only contain digits", ai));
+        }
+        if !data.chars().all(is_gs1_char) {
+            return Err(format!("GS1-128 AI ({}) data has characters GS1 doesn't allow", ai));
+        }
+        elements.push(Element {
+            ai: ai.to_string(),
+            data: data.to_string(),
+        });
+        rest = next;
+    }
+    Ok(elements)
+}
+
+/// `(01) 09501101020917 (10) ABC`, as printed under the bars.
+pub fn human_readable(elements: &[Element]) -> String {
+    elements
+        .iter()
+        .map(|element| format!("({}) {}", element.ai, element.data))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+enum Symbol {
+    Fnc1,
+    Char(u8),
+}
+
+/// `GS k` with Code 128 (m = 73). The data opens with FNC1, which is what
+/// makes it GS1-128, and another FNC1 ends each element whose AI isn't of
+/// predefined length, unless it is the last. Runs of four or more digits go in code set C, two
+/// digits per symbol, so a GTIN fits across 58mm paper. Everything else is
+/// code set B.
+pub fn barcode_command(elements: &[Element]) -> Result<Vec<u8>, String> {
+    let mut symbols = vec![Symbol::Fnc1];
+    for (index, element) in elements.iter().enumerate() {
+        symbols.extend(element.ai.bytes().chain(element.data.bytes()).map(Symbol::Char));
+        if !predefined_length(&element.ai) && index + 1 < elements.len() {
+            symbols.push(Symbol::Fnc1);
+        }
+    }
+
+    let mut data = Vec::new();
+    let mut set = None;
+    let mut index = 0;
+    while index < symbols.len() {
+        let digits = symbols[index..]
+            .iter()
+            .take_while(|symbol| matches!(symbol, Symbol::Char(c) if c.is_ascii_digit()))
+            .count();
+        if digits >= 4 || (set == Some(b'C') && digits >= 2) {
+            if set != Some(b'C') {
+                data.extend_from_slice(b"{C");
+                set = Some(b'C');
+            }
+            for pair in symbols[index..index + digits - digits % 2].chunks(2) {
+                if let [Symbol::Char(tens), Symbol::Char(ones)] = pair {
+                    data.push((tens - b'0') * 10 + (ones - b'0'));
+                }
+            }
+            index += digits - digits % 2;
+            continue;
+        }
+        match symbols[index] {
+            Symbol::Fnc1 => {
+                if set.is_none() {
+                    let ahead = symbols[index + 1..]
+                        .iter()
+                        .take_while(|symbol| matches!(symbol, Symbol::Char(c) if c.is_ascii_digit()))
+                        .count();
+                    let start = if ahead >= 4 { b'C' } else { b'B' };
+                    data.extend_from_slice(&[b'{', start]);
+                    set = Some(start);
+                }
+                data.extend_from_slice(b"{1");
+            }
+            Symbol::Char(c) => {
+                if set != Some(b'B') {
+                    data.extend_from_slice(b"{B");
+                    set = Some(b'B');
+                }
+                data.push(c);
+            }
+        }
+        index += 1;
+    }
+
+    if data.len() > u8::MAX as usize {
+        return Err("GS1-128 data is too long for one barcode".to_string());
+    }
+    let mut bytes = vec![0x1D, 0x6B, 73, data.len() as u8];
+    bytes.extend_from_slice(&data);
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(text: &str) -> Vec<u8> {
+        barcode_command(&parse(text).unwrap()).unwrap()
+    }
+
+    fn with_header(data: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0x1D, 0x6B, 73, data.len() as u8];
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    #[test]
+    fn gtin_is_all_code_set_c() {
+        let mut data = b"{C{1".to_vec();
+        data.extend_from_slice(&[1, 9, 50, 11, 1, 2, 9, 17]);
+        assert_eq!(encode("(01)09501101020917"), with_header(&data));
+    }
+
+    #[test]
+    fn odd_digit_run_ends_in_code_set_b() {
+        let mut data = b"{C{1".to_vec();
+        data.extend_from_slice(&[10, 12, 34]);
+        data.extend_from_slice(b"{B5");
+        assert_eq!(encode("(10)12345"), with_header(&data));
+    }
+
+    #[test]
+    fn variable_length_element_is_ended_by_fnc1() {
+        let mut data = b"{B{110AB{1{C".to_vec();
+        data.extend_from_slice(&[17, 25, 1, 1]);
+        assert_eq!(encode("(10)AB(17)250101"), with_header(&data));
+    }
+
+    #[test]
+    fn fixed_length_ai_outside_the_predefined_table_is_ended_by_fnc1() {
+        let mut data = b"{C{1".to_vec();
+        data.extend_from_slice(&[42, 20, 56]);
+        data.extend_from_slice(b"{1");
+        data.push(10);
+        data.extend_from_slice(b"{BAB");
+        assert_eq!(encode("(422)056(10)AB"), with_header(&data));
+    }
+
+    #[test]
+    fn unknown_ai_is_rejected() {
+        assert!(parse("(99)123").is_err());
+    }
+}
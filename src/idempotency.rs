@@ -0,0 +1,98 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::models::PrintedOn;
+
+/// How long a key is remembered after its job went through.
+const KEY_TTL: Duration = Duration::from_secs(5 * 60);
+/// Upper bound on remembered keys so a misbehaving client can't grow the map.
+const MAX_KEYS: usize = 1000;
+
+/// What happened to the job first sent with a key. Only outcomes worth
+/// replaying are kept; failed jobs are not, so a retry after an error prints.
+#[derive(Debug, Clone)]
+pub enum IdempotentOutcome {
+    /// The first request is still printing. A retry that arrives meanwhile
+    /// is turned away rather than printing a second copy alongside it.
+    InProgress,
+    Printed(PrintedOn),
+    Queued(String),
+}
+
+#[derive(Debug, Default)]
+pub struct IdempotencyCache {
+    entries: HashMap<String, (Instant, IdempotentOutcome)>,
+}
+
+impl IdempotencyCache {
+    pub fn get(&mut self, key: &str) -> Option<IdempotentOutcome> {
+        self.prune();
+        self.entries.get(key).map(|(_, outcome)| outcome.clone())
+    }
+
+    /// The outcome already recorded for `key`, or `None` after marking it in
+    /// progress. Both happen under one lock, so only one request wins a key.
+    pub fn claim(&mut self, key: &str) -> Option<IdempotentOutcome> {
+        if let Some(outcome) = self.get(key) {
+            return Some(outcome);
+        }
+        self.insert(key.to_string(), IdempotentOutcome::InProgress);
+        None
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        self.entries.remove(key);
+    }
+
+    pub fn insert(&mut self, key: String, outcome: IdempotentOutcome) {
+        self.prune();
+        if self.entries.len() >= MAX_KEYS && !self.entries.contains_key(&key) {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (seen, _))| *seen)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(key, (Instant::now(), outcome));
+    }
+
+    fn prune(&mut self) {
+        let now = Instant::now();
+        self.entries.retain(|_, (seen, _)| now.duration_since(*seen) < KEY_TTL);
+    }
+}
+
+/// A key held in progress by the request that claimed it. Dropped without
+/// `finish`, because the job failed or the request went away, it lets the
+/// key go so a retry prints.
+pub struct IdempotencyClaim {
+    cache: Arc<Mutex<IdempotencyCache>>,
+    key: Option<String>,
+}
+
+impl IdempotencyClaim {
+    pub fn new(cache: Arc<Mutex<IdempotencyCache>>, key: Option<String>) -> IdempotencyClaim {
+        IdempotencyClaim { cache, key }
+    }
+
+    /// Records the outcome for any retry of the key to replay.
+    pub fn finish(mut self, outcome: IdempotentOutcome) {
+        if let Some(key) = self.key.take() {
+            self.cache.lock().unwrap().insert(key, outcome);
+        }
+    }
+}
+
+impl Drop for IdempotencyClaim {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.cache.lock().unwrap().remove(&key);
+        }
+    }
+}
@@ -1,14 +1,29 @@
 use print::initialize_device;
+use crate::config::AppConfig;
 use crate::server::run;
+use crate::state::AppState;
 
+mod config;
+mod driver;
+mod error;
+mod gs1;
 mod server;
 mod print;
+mod idempotency;
+mod metrics;
 mod models;
+mod preview;
+mod raster;
+mod spool;
+mod state;
+mod usb;
 
 
 #[tokio::main(flavor="current_thread")]
 async fn main() {
-    let device =  initialize_device().await;
+    let mut config = AppConfig::load();
+    config.printer.resolve_auto_preset();
+    let device =  initialize_device(&config.printer).await;
 
-    run(device).await;
+    run(AppState::new(device, config)).await;
 }
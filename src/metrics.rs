@@ -0,0 +1,43 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters behind `GET /metrics`, shared through `AppState`.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    prints: AtomicU64,
+    print_failures: AtomicU64,
+    reconnects: AtomicU64,
+}
+
+impl Metrics {
+    pub fn record_print(&self) {
+        self.prints.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_print_failure(&self) {
+        self.print_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_reconnect(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The counters in the Prometheus text exposition format.
+    pub fn render(&self, online: bool) -> String {
+        let mut out = String::new();
+        let counters = [
+            ("reika_prints_total", "Receipts printed.", &self.prints),
+            ("reika_print_failures_total", "Receipts that failed to print.", &self.print_failures),
+            ("reika_reconnects_total", "Attempts to reopen the printer after a failure.", &self.reconnects),
+        ];
+        for (name, help, value) in counters {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+        }
+        let _ = writeln!(out, "# HELP reika_printer_online Whether the printer is currently online.");
+        let _ = writeln!(out, "# TYPE reika_printer_online gauge");
+        let _ = writeln!(out, "reika_printer_online {}", online as u8);
+        out
+    }
+}
@@ -1,6 +1,16 @@
-use escpos::{driver::UsbDriver, errors::PrinterError, printer::Printer, utils::{CashDrawer, CharacterSet, Font, JustifyMode, PageCode, Protocol, UnderlineMode}};
+use escpos::{errors::PrinterError, printer::Printer, utils::{BarcodeFont, BarcodeHeight, BarcodeOption, BarcodePosition, BarcodeWidth, BitImageOption, BitImageSize, CashDrawer, CharacterSet, Font, JustifyMode, PageCode, Protocol, QRCodeCorrectionLevel, QRCodeModel, QRCodeOption, UnderlineMode}};
 use serde::{Deserialize, Serialize};
 
+use crate::config::PrinterConfig;
+use crate::driver::{DryRunDriver, PrinterDriver};
+use crate::error::JSON_ERROR_MESSAGE;
+use crate::gs1;
+use crate::print::{blocking_exchange, transmit_status};
+use crate::raster::{check_image, paper_width_dots, prepare_image};
+
+/// Characters per line on 58mm paper with the default font A. 80mm paper fits 48.
+pub const RECEIPT_WIDTH_CHARS: usize = 32;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PrinterTestSchema {
     test_page: bool,
@@ -8,6 +18,13 @@ pub struct PrinterTestSchema {
 }
 
 impl PrinterTestSchema {
+    /// Just the test line, no test page.
+    pub fn line(text: &str) -> PrinterTestSchema {
+        PrinterTestSchema {
+            test_page: false,
+            test_line: text.to_string(),
+        }
+    }
     pub fn test_line(&self) -> &str {
         &self.test_line
     }
@@ -20,9 +37,428 @@ impl PrinterTestSchema {
 pub struct StatusResponse {
     pub is_connected: bool,
     pub error: String,
+    pub paper_out: bool,
+    pub paper_low: bool,
+    pub cover_open: bool,
+}
+
+/// What the printer reported through DLE EOT. All are `false` when it
+/// doesn't answer status requests at all.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct DeviceStatus {
+    pub paper_out: bool,
+    /// The near-end sensor: the roll is running low but still printing.
+    pub paper_low: bool,
+    pub cover_open: bool,
+}
+
+/// Printer connection state. `Reconnecting` covers the seconds between a
+/// failed write and the next open, so a routine replug doesn't read as an outage.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PrinterStatus {
+    Online,
+    Reconnecting,
+    Offline,
+}
+
+impl PrinterStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PrinterStatus::Online => "online",
+            PrinterStatus::Reconnecting => "reconnecting",
+            PrinterStatus::Offline => "offline",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HealthResponse {
+    pub status: String,
+    pub printer_online: bool,
+    pub printer_status: PrinterStatus,
+    /// Seconds since the printer went reconnecting or offline; `None` while
+    /// it's online.
+    pub offline_secs: Option<u64>,
+    /// Last known state of the backup printer, `None` when there is none.
+    pub backup_status: Option<PrinterStatus>,
+}
+
+/// Which printer a job came out of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrintedOn {
+    Main,
+    Backup,
+}
+
+impl PrintedOn {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PrintedOn::Main => "main",
+            PrintedOn::Backup => "backup",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VersionResponse {
+    pub version: String,
+    pub preset: String,
+}
+
+/// A problem found in a print body. `index` is the offending command's
+/// position, or `None` when the body didn't parse at all.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CommandError {
+    pub index: Option<usize>,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ValidationResponse {
+    pub valid: bool,
+    pub errors: Vec<CommandError>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PrintQuery {
+    pub dry_run: Option<String>,
+}
+
+impl PrintQuery {
+    pub fn is_dry_run(&self) -> bool {
+        matches!(self.dry_run.as_deref(), Some("1") | Some("true"))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DryRunResponse {
+    pub length: usize,
+    pub bytes: String,
+}
+
+/// Body of a failed print or drawer request. `code` names the failure, e.g.
+/// `MAINTENANCE`, and `retryable` is `true` when the same request may
+/// succeed later, e.g. once the printer is back.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ErrorResponse {
+    pub error: String,
+    pub code: String,
+    pub retryable: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RawPrintResponse {
+    pub bytes_written: usize,
+}
+
+/// Body of `/print/batch`. The jobs print back to back with no other job
+/// between them.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BatchRequest {
+    pub jobs: Vec<Commands>,
+    /// Printed between consecutive jobs, e.g. a few feeds. Each job still
+    /// ends with its own cut.
+    pub separator: Option<Vec<Command>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BatchJobResult {
+    pub printed: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BatchResponse {
+    pub results: Vec<BatchJobResult>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QueuedResponse {
+    pub job_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UsbEndpointInfo {
+    pub interface: u8,
+    pub address: u8,
+    pub direction: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UsbDeviceInfo {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub bulk_endpoints: Vec<UsbEndpointInfo>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum MaintenanceMode {
+    On,
+    Off,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MaintenanceRequest {
+    pub mode: MaintenanceMode,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MaintenanceResponse {
+    pub maintenance: bool,
+}
+
+/// Pulse length defaults for `ESC p` when only one of the two is given, in the
+/// printer's 2 ms units: 50 ms on, 500 ms off.
+const DRAWER_ON_TIME: u8 = 25;
+const DRAWER_OFF_TIME: u8 = 250;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DrawerParams {
+    pub pin: CashDrawer,
+    /// Pulse on time in 2 ms units (0-255).
+    pub on_time: Option<u8>,
+    /// Pulse off time in 2 ms units (0-255).
+    pub off_time: Option<u8>,
+}
+
+/// Drawer kick. A bare pin uses the escpos default pulse; the object form
+/// sets the timing for solenoids that need a longer kick.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum DrawerKick {
+    Pin(CashDrawer),
+    WithTiming(DrawerParams),
+}
+
+impl DrawerKick {
+    /// The raw `ESC p m t1 t2` bytes, or `None` when no timing was given.
+    fn pulse_command(&self) -> Option<[u8; 5]> {
+        let params = match self {
+            DrawerKick::Pin(_) => return None,
+            DrawerKick::WithTiming(params) => params,
+        };
+        if params.on_time.is_none() && params.off_time.is_none() {
+            return None;
+        }
+        let pin = match params.pin {
+            CashDrawer::Pin2 => 0,
+            CashDrawer::Pin5 => 1,
+        };
+        Some([
+            0x1B,
+            0x70,
+            pin,
+            params.on_time.unwrap_or(DRAWER_ON_TIME),
+            params.off_time.unwrap_or(DRAWER_OFF_TIME),
+        ])
+    }
+
+    fn pin(&self) -> CashDrawer {
+        match self {
+            DrawerKick::Pin(pin) => *pin,
+            DrawerKick::WithTiming(params) => params.pin,
+        }
+    }
+}
+
+/// How a job is cut when its commands don't end with a cut of their own.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum CutType {
+    Full,
+    /// Leaves a small hinge so the receipt doesn't drop or get torn crooked.
+    Partial,
+    None,
+}
+
+/// Ink for two-color thermal stock. Single-color printers ignore the command.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum ReceiptColor {
+    Black,
+    Red,
+}
+
+/// Where the human-readable interpretation (HRI) text goes around a barcode.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum HriPosition {
+    None,
+    Above,
+    Below,
+    Both,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum HriFont {
+    A,
+    B,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImageParams {
+    pub path: String,
+    /// Where the image sits across the paper. Defaults to left.
+    pub align: Option<JustifyMode>,
+    /// Scales wider images down to this, keeping the aspect ratio. Never
+    /// more than the print area.
+    pub max_width_dots: Option<u16>,
+    /// Error-diffusion dithering instead of a hard threshold, for photos.
+    pub dither: Option<bool>,
+}
+
+/// An image file to print. A bare string is just the path and is printed
+/// as escpos rasterizes it; the object form lays it out first.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum ImageSource {
+    Path(String),
+    WithOptions(ImageParams),
+}
+
+impl ImageSource {
+    pub fn path(&self) -> &str {
+        match self {
+            ImageSource::Path(path) => path,
+            ImageSource::WithOptions(params) => &params.path,
+        }
+    }
+}
+
+/// QR error correction, from L (about 7% recoverable) to H (about 30%).
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum QrEc {
+    L,
+    M,
+    Q,
+    H,
+}
+
+/// Module sizes the QR command accepts.
+pub const QR_SIZES: std::ops::RangeInclusive<u8> = 1..=16;
+const DEFAULT_QR_SIZE: u8 = 6;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QrParams {
+    pub data: String,
+    /// Module size in dots. Larger codes scan better off glossy paper.
+    pub size: Option<u8>,
+    pub error_correction: Option<QrEc>,
+}
+
+/// QR parameters. A bare string is just the data and uses the escpos
+/// defaults, like `BarcodeData`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum QrData {
+    Data(String),
+    WithOptions(QrParams),
+}
+
+impl QrData {
+    pub fn data(&self) -> &str {
+        match self {
+            QrData::Data(data) => data,
+            QrData::WithOptions(params) => &params.data,
+        }
+    }
+
+    /// Module size and error correction when given as an object. Missing
+    /// fields default to size 6 and level M.
+    pub fn size_and_level(&self) -> Option<(u8, QrEc)> {
+        match self {
+            QrData::Data(_) => None,
+            QrData::WithOptions(params) => Some((
+                params.size.unwrap_or(DEFAULT_QR_SIZE),
+                params.error_correction.unwrap_or(QrEc::M),
+            )),
+        }
+    }
+
+    pub fn option(&self) -> Option<QRCodeOption> {
+        let (size, level) = self.size_and_level()?;
+        let level = match level {
+            QrEc::L => QRCodeCorrectionLevel::L,
+            QrEc::M => QRCodeCorrectionLevel::M,
+            QrEc::Q => QRCodeCorrectionLevel::Q,
+            QrEc::H => QRCodeCorrectionLevel::H,
+        };
+        Some(QRCodeOption::new(QRCodeModel::Model2, size, level))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum BarcodeSize {
+    XS,
+    S,
+    M,
+    L,
+    XL,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BarcodeParams {
+    pub data: String,
+    pub hri_position: Option<HriPosition>,
+    pub hri_font: Option<HriFont>,
+    pub height: Option<BarcodeSize>,
+    pub width: Option<BarcodeSize>,
+}
+
+/// Barcode parameters. A bare string is just the data and prints with the
+/// escpos defaults, so existing payloads keep working.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum BarcodeData {
+    Data(String),
+    WithOptions(BarcodeParams),
+}
+
+impl BarcodeData {
+    pub fn data(&self) -> &str {
+        match self {
+            BarcodeData::Data(data) => data,
+            BarcodeData::WithOptions(params) => &params.data,
+        }
+    }
+
+    /// The escpos options to print with, or `None` to use the plain builder.
+    /// Fields left out of the object default to M width/height, font A, HRI below.
+    pub fn option(&self) -> Option<BarcodeOption> {
+        let params = match self {
+            BarcodeData::Data(_) => return None,
+            BarcodeData::WithOptions(params) => params,
+        };
+        let width = match params.width.unwrap_or(BarcodeSize::M) {
+            BarcodeSize::XS => BarcodeWidth::XS,
+            BarcodeSize::S => BarcodeWidth::S,
+            BarcodeSize::M => BarcodeWidth::M,
+            BarcodeSize::L => BarcodeWidth::L,
+            BarcodeSize::XL => BarcodeWidth::XL,
+        };
+        let height = match params.height.unwrap_or(BarcodeSize::M) {
+            BarcodeSize::XS => BarcodeHeight::XS,
+            BarcodeSize::S => BarcodeHeight::S,
+            BarcodeSize::M => BarcodeHeight::M,
+            BarcodeSize::L => BarcodeHeight::L,
+            BarcodeSize::XL => BarcodeHeight::XL,
+        };
+        let font = match params.hri_font.unwrap_or(HriFont::A) {
+            HriFont::A => BarcodeFont::A,
+            HriFont::B => BarcodeFont::B,
+        };
+        let position = match params.hri_position.unwrap_or(HriPosition::Below) {
+            HriPosition::None => BarcodePosition::None,
+            HriPosition::Above => BarcodePosition::Above,
+            HriPosition::Below => BarcodePosition::Below,
+            HriPosition::Both => BarcodePosition::Both,
+        };
+        Some(BarcodeOption::new(width, height, font, position))
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "command", content = "parameters")]
 pub enum Command {
     Print(Option<()>),
@@ -48,88 +484,682 @@ pub enum Command {
     LineSpacing(u8),
     ResetLineSpacing(Option<()>),
     UpsideDown(bool),
-    CashDrawer(CashDrawer),
+    CashDrawer(DrawerKick),
     Write(String),
     Writeln(String),
-    Ean13(String),
-    Ean8(String),
-    Upca(String),
-    Upce(String),
-    Code39(String),
-    Codabar(String),
-    Itf(String),
-    Qrcode(String),
+    Ean13(BarcodeData),
+    Ean8(BarcodeData),
+    Upca(BarcodeData),
+    Upce(BarcodeData),
+    Code39(BarcodeData),
+    Codabar(BarcodeData),
+    Itf(BarcodeData),
+    /// GS1-128 from AI-bracketed text like `(01)09501101020917(10)ABC123`.
+    #[serde(rename = "GS1_128")]
+    Gs1128(String),
+    Qrcode(QrData),
     GS1Databar2d(String),
     Pdf417(String),
     MaxiCode(String),
     DataMatrix(String),
     Aztec(String),
-    // BitImage(String),
+    /// Centered bold title between two paper-width rules, for splitting kitchen
+    /// tickets by station. Bold and justification are reset afterwards.
+    SectionHeader(String),
+    /// One line with `left` flush left and `right` flush right, padded to the
+    /// paper width at the current character size.
+    Row { left: String, right: String },
+    /// Selects the print color (ESC r). Init resets it to black.
+    Color(ReceiptColor),
+    /// Absolute path to an image on this machine, printed as a raster after
+    /// conversion to 1-bit. Must be inside the configured `image_dir`.
+    ImageFile(ImageSource),
+    /// Horizontal tab stops as ascending character columns (ESC D). They are
+    /// kept across `Init` and `Reset`; an empty list clears them.
+    SetTabs(Vec<u8>),
+    /// Moves to the next tab stop (HT).
+    Tab(Option<()>),
+    /// A full line of the given character, `-` by default, sized to the paper
+    /// width at the current font and character size.
+    Divider(Option<char>),
+    /// Sets all of these at once with one `ESC ! n`. Anything left out is
+    /// turned off. Font C has no bit of its own and prints as Font B.
+    PrintMode {
+        font: Option<Font>,
+        bold: Option<bool>,
+        double_height: Option<bool>,
+        double_width: Option<bool>,
+        underline: Option<bool>,
+    },
+    /// This machine's local time as a line, in a chrono format string;
+    /// `%Y-%m-%d %H:%M:%S` by default.
+    DateTime(Option<String>),
+    /// A logo stored in the printer's NV memory (FS p). `slot` is its image
+    /// number from 1; `mode` 0 is normal, 1 double width, 2 double height,
+    /// 3 both.
+    NvLogo { slot: u8, mode: u8 },
+    /// A `PAPER: OK`, `LOW` or `OUT` line read from the roll sensor, for
+    /// end-of-day reports. `UNKNOWN` when the printer doesn't report it.
+    PaperStatus(Option<()>),
+    /// Moves the print position to this many dots from the start of the
+    /// line (ESC $), for fields that sit at fixed offsets.
+    AbsolutePosition(u16),
+    /// Moves the print position by this many dots, left when negative
+    /// (`ESC \`).
+    RelativePosition(i16),
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Commands {
     pub commands: Vec<Command>,
+    /// `false` ends the job after the last command without the usual feed and
+    /// cut, for back-to-back jobs on continuous media. Defaults to `true`.
+    pub auto_cut: Option<bool>,
+    /// Code page the job's text is printed in. It is selected after every
+    /// init and text is transcoded from UTF-8 into it, with `?` standing in
+    /// for characters the page doesn't have.
+    pub encoding: Option<PageCode>,
+    /// `true` leaves out the configured `header_commands` and
+    /// `footer_commands` for this job.
+    pub skip_header_footer: Option<bool>,
+    /// Caller's name for the job, e.g. an order number, used in log lines.
+    pub label: Option<String>,
+    /// `true` prints the whole job upside down (ESC {), for printers that
+    /// feed toward the customer. Turned back off before the final cut.
+    pub rotate_180: Option<bool>,
+}
+
+impl Commands {
+    /// `"<label> (<n> commands)"`, or a generic name when there is no label.
+    pub fn summary(&self) -> String {
+        format!("{} ({} commands)", self.label.as_deref().unwrap_or("Print job"), self.commands.len())
+    }
+}
+
+/// Puts the configured header and footer around the job's own commands,
+/// unless the job opted out. The result is marked so it isn't wrapped twice.
+pub fn with_header_footer(mut commands: Commands, config: &PrinterConfig) -> Commands {
+    if commands.skip_header_footer.unwrap_or(false) {
+        return commands;
+    }
+    let mut wrapped = config.header_commands.clone();
+    wrapped.append(&mut commands.commands);
+    wrapped.extend(config.footer_commands.iter().cloned());
+    commands.commands = wrapped;
+    commands.skip_header_footer = Some(true);
+    commands
+}
+
+/// Characters per line in `font`, given the Font A count. Fonts B and C are
+/// 9 dots wide against Font A's 12, so 32 columns become 42 and 48 become 64.
+pub fn font_columns(font_a_columns: usize, font: Font) -> usize {
+    match font {
+        Font::A => font_a_columns,
+        Font::B | Font::C => font_a_columns * 4 / 3,
+    }
+}
+
+/// Pads `left` and `right` apart so together they fill `columns`. The left
+/// text is shortened when both don't fit, keeping prices intact.
+pub fn layout_row(left: &str, right: &str, columns: usize) -> String {
+    let right_len = right.chars().count();
+    let left: String = left.chars().take(columns.saturating_sub(right_len + 1)).collect();
+    let gap = columns.saturating_sub(left.chars().count() + right_len).max(1);
+    format!("{}{}{}", left, " ".repeat(gap), right)
+}
+
+/// Canonical form of `path` if it is inside `image_dir`. Both sides are
+/// canonicalized first so `..` segments and symlinks can't escape it.
+pub fn resolve_image_path(path: &str, image_dir: &str) -> Result<String, PrinterError> {
+    if image_dir.is_empty() {
+        return Err(PrinterError::Input("ImageFile is disabled, no image_dir is configured".to_string()));
+    }
+    let allowed = std::fs::canonicalize(image_dir)
+        .map_err(|e| PrinterError::Input(format!("image_dir {:?} is not usable: {}", image_dir, e)))?;
+    let resolved = std::fs::canonicalize(path)
+        .map_err(|e| PrinterError::Input(format!("image {:?} can't be read: {}", path, e)))?;
+    if !resolved.starts_with(&allowed) {
+        return Err(PrinterError::Input(format!("image {:?} is outside the allowed image_dir", path)));
+    }
+    resolved
+        .to_str()
+        .map(str::to_string)
+        .ok_or_else(|| PrinterError::Input(format!("image {:?} has a non UTF-8 path", path)))
+}
+
+/// Rejects the job up front if any `ImageFile` points outside `image_dir`
+/// or at a file that isn't an image, before a single byte of it is sent.
+pub fn check_image_paths(commands: &Commands, image_dir: &str) -> Result<(), PrinterError> {
+    for command in &commands.commands {
+        if let Command::ImageFile(image) = command {
+            check_image(&resolve_image_path(image.path(), image_dir)?)?;
+        }
+    }
+    Ok(())
+}
+
+/// `text` as it comes out in `page_code`: every character the page can't
+/// encode becomes `?` and is also returned, for the caller to report.
+/// escpos does the actual encoding; a throwaway printer is used to ask it
+/// what it can map. `None` is the printer's default page, left as is.
+pub fn fit_to_page_code(text: &str, page_code: Option<PageCode>) -> (String, Vec<char>) {
+    let page_code = match page_code {
+        Some(page_code) => page_code,
+        None => return (text.to_string(), Vec::new()),
+    };
+    let mut probe = Printer::new(PrinterDriver::DryRun(DryRunDriver::default()), Protocol::default(), None);
+    if probe.page_code(page_code).is_err() {
+        return (text.to_string(), Vec::new());
+    }
+    let mut missing = Vec::new();
+    let fitted = text
+        .chars()
+        .map(|c| {
+            if c.is_ascii() || probe.write(&c.to_string()).is_ok() {
+                c
+            } else {
+                missing.push(c);
+                '?'
+            }
+        })
+        .collect();
+    (fitted, missing)
+}
+
+/// Text to send in `page_code`. Unmappable characters are logged and printed
+/// as `?`, so one stray glyph doesn't fail the whole job.
+fn encode_text(text: &str, page_code: Option<PageCode>) -> String {
+    let (fitted, missing) = fit_to_page_code(text, page_code);
+    if let Some(page_code) = page_code {
+        for c in missing {
+            println!("Character {:?} is not in code page {:?}, printing '?' instead.", c, page_code);
+        }
+    }
+    fitted
+}
+
+/// Reads the roll sensor (DLE EOT 4) straight from the driver. Bytes queued
+/// in the printer aren't sent yet, but they don't change what it reports.
+/// A file, dry run or spooler connection can't answer, so it prints UNKNOWN.
+/// The read blocks, so it runs on the blocking pool like the other status
+/// requests.
+async fn paper_status_line(driver: &PrinterDriver) -> Result<String, PrinterError> {
+    let status = blocking_exchange(driver.clone(), |driver| transmit_status(driver, 4)).await?;
+    let status = match status {
+        Some(status) if status & 0x60 != 0 => "OUT",
+        Some(status) if status & 0x0C != 0 => "LOW",
+        Some(_) => "OK",
+        None => "UNKNOWN",
+    };
+    Ok(format!("PAPER: {}", status))
+}
+
+const DEFAULT_DIVIDER: char = '-';
+const DEFAULT_DATETIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// The current local time in `format`. chrono only reports a bad format
+/// when displayed, so it's written out here rather than with `to_string`,
+/// which would panic.
+pub fn format_datetime(format: Option<&str>) -> Result<String, String> {
+    use std::fmt::Write;
+    let format = format.unwrap_or(DEFAULT_DATETIME_FORMAT);
+    let mut text = String::new();
+    write!(text, "{}", chrono::Local::now().format(format)).map_err(|_| format!("DateTime format {:?} is not valid", format))?;
+    Ok(text)
+}
+
+/// Rejects the job up front if a `DateTime` format can't be written,
+/// rather than once the job already holds the printer.
+pub fn check_datetime_formats(commands: &Commands) -> Result<(), PrinterError> {
+    for command in &commands.commands {
+        if let Command::DateTime(format) = command {
+            format_datetime(format.as_deref()).map_err(PrinterError::Input)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn divider_line(character: Option<char>, columns: usize) -> String {
+    character.unwrap_or(DEFAULT_DIVIDER).to_string().repeat(columns)
+}
+
+/// The `n` of `ESC ! n`: bit 0 font B, 3 emphasized, 4 double height,
+/// 5 double width, 7 underline.
+fn print_mode_byte(font: Option<Font>, bold: Option<bool>, double_height: Option<bool>, double_width: Option<bool>, underline: Option<bool>) -> u8 {
+    let mut n = 0;
+    if matches!(font, Some(Font::B) | Some(Font::C)) {
+        n |= 0x01;
+    }
+    if bold.unwrap_or(false) {
+        n |= 0x08;
+    }
+    if double_height.unwrap_or(false) {
+        n |= 0x10;
+    }
+    if double_width.unwrap_or(false) {
+        n |= 0x20;
+    }
+    if underline.unwrap_or(false) {
+        n |= 0x80;
+    }
+    n
+}
+
+/// Most printers accept up to 32 tab stops.
+const MAX_TAB_STOPS: usize = 32;
+
+fn tab_stops_command(stops: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![0x1B, 0x44];
+    bytes.extend_from_slice(stops);
+    bytes.push(0x00);
+    bytes
+}
+
+/// Init and Reset clear the code page, tab stops and upside-down mode on the
+/// printer; this puts back the ones the job asked for.
+fn restore_after_init(printer: &mut Printer<PrinterDriver>, encoding: Option<PageCode>, tabs: &[u8], rotate: bool) -> Result<(), PrinterError> {
+    if let Some(page_code) = encoding {
+        printer.page_code(page_code)?;
+    }
+    if rotate {
+        printer.upside_down(true)?;
+    }
+    if !tabs.is_empty() {
+        printer.custom(&tab_stops_command(tabs))?;
+    }
+    Ok(())
+}
+
+fn check_digits(kind: &str, data: &str, lengths: std::ops::RangeInclusive<usize>) -> Result<(), String> {
+    if !data.chars().all(|c| c.is_ascii_digit()) {
+        return Err(format!("{} data must only contain digits", kind));
+    }
+    if !lengths.contains(&data.len()) {
+        return Err(format!("{} data must be {} to {} digits long", kind, lengths.start(), lengths.end()));
+    }
+    Ok(())
+}
+
+fn validate_command(command: &Command) -> Result<(), String> {
+    match command {
+        Command::Ean13(code) => check_digits("EAN13", code.data(), 12..=13),
+        Command::Ean8(code) => check_digits("EAN8", code.data(), 7..=8),
+        Command::Upca(code) => check_digits("UPC-A", code.data(), 11..=12),
+        Command::Upce(code) => check_digits("UPC-E", code.data(), 6..=8),
+        Command::Itf(code) => check_digits("ITF", code.data(), 2..=255),
+        Command::DateTime(format) => format_datetime(format.as_deref()).map(|_| ()),
+        Command::NvLogo { slot: 0, .. } => Err("NvLogo slot must be within 1..=255".to_string()),
+        Command::NvLogo { mode, .. } if *mode > 3 => Err("NvLogo mode must be within 0..=3".to_string()),
+        Command::Gs1128(text) => gs1::parse(text).and_then(|elements| gs1::barcode_command(&elements)).map(|_| ()),
+        Command::Code39(code) | Command::Codabar(code) if code.data().is_empty() => {
+            Err("Barcode data must not be empty".to_string())
+        }
+        Command::Size((width, height)) if !(1..=8).contains(width) || !(1..=8).contains(height) => {
+            Err("Size width and height must be within 1..=8".to_string())
+        }
+        Command::Qrcode(code) if code.data().is_empty() => Err("Code data must not be empty".to_string()),
+        Command::Qrcode(code) if code.size_and_level().is_some_and(|(size, _)| !QR_SIZES.contains(&size)) => {
+            Err(format!("QR size must be within {}..={}", QR_SIZES.start(), QR_SIZES.end()))
+        }
+        Command::GS1Databar2d(data)
+        | Command::Pdf417(data)
+        | Command::MaxiCode(data)
+        | Command::DataMatrix(data)
+        | Command::Aztec(data) if data.is_empty() => Err("Code data must not be empty".to_string()),
+        Command::SetTabs(stops) if stops.len() > MAX_TAB_STOPS => {
+            Err(format!("SetTabs accepts at most {} stops", MAX_TAB_STOPS))
+        }
+        Command::SetTabs(stops) if stops.contains(&0) || stops.windows(2).any(|pair| pair[0] >= pair[1]) => {
+            Err("SetTabs stops must be ascending columns above 0".to_string())
+        }
+        Command::ImageFile(image) if !std::path::Path::new(image.path()).is_absolute() => {
+            Err("ImageFile path must be absolute".to_string())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Checks the values inside otherwise well-formed commands, catching what
+/// would only fail (or print garbage) halfway through a job.
+pub fn validate_commands(commands: &Commands) -> Vec<CommandError> {
+    commands
+        .commands
+        .iter()
+        .enumerate()
+        .filter_map(|(index, command)| {
+            validate_command(command).err().map(|message| CommandError {
+                index: Some(index),
+                message,
+            })
+        })
+        .collect()
 }
 
 pub fn parse_json(json_data: &str) -> Result<Commands, PrinterError> {
     println!("Parsing a print request! {:#?}", json_data);
-    let commands: Commands = serde_json::from_str(json_data).map_err(|e| PrinterError::Input(e.to_string()))?;
+    let commands: Commands =
+        serde_json::from_str(json_data).map_err(|e| PrinterError::Input(format!("{} {}", JSON_ERROR_MESSAGE, e)))?;
     println!("{:?}", commands);
     Ok(commands)
 }
 
-pub async fn execute_commands(driver: UsbDriver, commands: Commands) -> Result<(), PrinterError> {
+pub async fn execute_commands(driver: PrinterDriver, commands: Commands, config: &PrinterConfig) -> Result<(), PrinterError> {
+    let commands = with_header_footer(commands, config);
+    driver.start_job()?;
     let mut printer = Printer::new(driver.clone(), Protocol::default(), None);
     
-    printer.init()?;
+    if config.auto_init_per_job {
+        printer.init()?;
+    }
+    // Width multiplier from the last Size, so Row knows how many columns fit.
+    let mut size_width: u8 = 1;
+    // And the font, since Font B fits more characters on a line.
+    let mut font = Font::A;
+    // Tab stops set by the job, restored after every Init and Reset.
+    let mut tabs: Vec<u8> = Vec::new();
+    // Whether the last command was already a cut, so the job end doesn't add another.
+    let mut ends_with_cut = false;
+    let auto_cut = commands.auto_cut.unwrap_or(true);
+    let encoding = commands.encoding;
+    // The page text is encoded in: the job's encoding, or the last PageCode.
+    let mut active_page = encoding;
+    if let Some(page_code) = encoding {
+        printer.page_code(page_code)?;
+    }
+    let rotate = commands.rotate_180.unwrap_or(false);
+    if rotate {
+        printer.upside_down(true)?;
+    }
     
     for command in commands.commands {
-        
+        ends_with_cut = matches!(command, Command::Cut(_) | Command::PartialCut(_) | Command::PrintCut(_));
         match command {
             Command::Print(_) => printer.print()?,
-            Command::Init(_) => printer.init()?,
-            Command::Reset(_) => printer.reset()?,
+            Command::Init(_) => {
+                size_width = 1;
+                font = Font::A;
+                active_page = encoding;
+                printer.init()?;
+                restore_after_init(&mut printer, encoding, &tabs, rotate)?;
+                &mut printer
+            }
+            Command::Reset(_) => {
+                size_width = 1;
+                font = Font::A;
+                active_page = encoding;
+                printer.reset()?;
+                restore_after_init(&mut printer, encoding, &tabs, rotate)?;
+                &mut printer
+            }
             Command::Cut(_) => printer.cut()?,
             Command::PartialCut(_) => printer.partial_cut()?,
             Command::PrintCut(_) => printer.print_cut()?,
-            Command::PageCode(page_code) => printer.page_code(page_code)?,
+            Command::PageCode(page_code) => {
+                active_page = Some(page_code);
+                printer.page_code(page_code)?
+            }
             Command::CharacterSet(char_set) => printer.character_set(char_set)?,
             Command::Bold(enabled) => printer.bold(enabled)?,
             Command::Underline(mode) => printer.underline(mode)?,
             Command::DoubleStrike(enabled) => printer.double_strike(enabled)?,
-            Command::Font(font) => printer.font(font)?,
+            Command::Font(selected) => {
+                font = selected;
+                printer.font(selected)?
+            }
             Command::Flip(enabled) => printer.flip(enabled)?,
             Command::Justify(mode) => printer.justify(mode)?,
             Command::Reverse(enabled) => printer.reverse(enabled)?,
-            Command::Size((width, height)) => printer.size(width, height)?,
-            Command::ResetSize(_) => printer.reset_size()?,
+            Command::Size((width, height)) => {
+                size_width = width.max(1);
+                printer.size(width, height)?
+            }
+            Command::ResetSize(_) => {
+                size_width = 1;
+                printer.reset_size()?
+            }
             Command::Smoothing(enabled) => printer.smoothing(enabled)?,
             Command::Feed(_) => printer.feed()?,
             Command::Feeds(lines) => printer.feeds(lines)?,
             Command::LineSpacing(value) => printer.line_spacing(value)?,
             Command::ResetLineSpacing(_) => printer.reset_line_spacing()?,
             Command::UpsideDown(enabled) => printer.upside_down(enabled)?,
-            Command::CashDrawer(pin) => printer.cash_drawer(pin)?,
-            Command::Write(text) => printer.write(&text)?,
-            Command::Writeln(text) => printer.writeln(&text)?,
-            Command::Ean13(data) => printer.ean13(&data)?,
-            Command::Ean8(data) => printer.ean8(&data)?,
-            Command::Upca(data) => printer.upca(&data)?,
-            Command::Upce(data) => printer.upce(&data)?,
-            Command::Code39(data) => printer.code39(&data)?,
-            Command::Codabar(data) => printer.codabar(&data)?,
-            Command::Itf(data) => printer.itf(&data)?,
-            Command::Qrcode(data) => printer.qrcode(&data)?,
+            Command::CashDrawer(kick) => match kick.pulse_command() {
+                Some(bytes) => printer.custom(&bytes)?,
+                None => printer.cash_drawer(kick.pin())?,
+            },
+            Command::Write(text) => printer.write(&encode_text(&text, active_page))?,
+            Command::Writeln(text) => printer.writeln(&encode_text(&text, active_page))?,
+            Command::DateTime(format) => {
+                let text = format_datetime(format.as_deref()).map_err(PrinterError::Input)?;
+                printer.writeln(&encode_text(&text, active_page))?
+            }
+            Command::NvLogo { slot, mode } => {
+                // Also checked here, for jobs that never went through validation.
+                validate_command(&Command::NvLogo { slot, mode }).map_err(PrinterError::Input)?;
+                printer.custom(&[0x1C, 0x70, slot, mode])?
+            }
+            Command::PaperStatus(_) => printer.writeln(&paper_status_line(&driver).await?)?,
+            Command::AbsolutePosition(dots) => {
+                let [low, high] = dots.to_le_bytes();
+                printer.custom(&[0x1B, 0x24, low, high])?
+            }
+            Command::RelativePosition(dots) => {
+                let [low, high] = dots.to_le_bytes();
+                printer.custom(&[0x1B, 0x5C, low, high])?
+            }
+            Command::Ean13(code) => match code.option() {
+                Some(option) => printer.ean13_option(code.data(), option)?,
+                None => printer.ean13(code.data())?,
+            },
+            Command::Ean8(code) => match code.option() {
+                Some(option) => printer.ean8_option(code.data(), option)?,
+                None => printer.ean8(code.data())?,
+            },
+            Command::Upca(code) => match code.option() {
+                Some(option) => printer.upca_option(code.data(), option)?,
+                None => printer.upca(code.data())?,
+            },
+            Command::Upce(code) => match code.option() {
+                Some(option) => printer.upce_option(code.data(), option)?,
+                None => printer.upce(code.data())?,
+            },
+            Command::Code39(code) => match code.option() {
+                Some(option) => printer.code39_option(code.data(), option)?,
+                None => printer.code39(code.data())?,
+            },
+            Command::Codabar(code) => match code.option() {
+                Some(option) => printer.codabar_option(code.data(), option)?,
+                None => printer.codabar(code.data())?,
+            },
+            Command::Itf(code) => match code.option() {
+                Some(option) => printer.itf_option(code.data(), option)?,
+                None => printer.itf(code.data())?,
+            },
+            Command::Gs1128(text) => {
+                let bytes = gs1::parse(&text)
+                    .and_then(|elements| gs1::barcode_command(&elements))
+                    .map_err(PrinterError::Input)?;
+                printer.custom(&bytes)?
+            }
+            Command::Qrcode(code) => match code.option() {
+                Some(option) => printer.qrcode_option(code.data(), option)?,
+                None => printer.qrcode(code.data())?,
+            },
             Command::GS1Databar2d(data) => printer.gs1_databar_2d(&data)?,
             Command::Pdf417(data) => printer.pdf417(&data)?,
             Command::MaxiCode(data) => printer.maxi_code(&data)?,
             Command::DataMatrix(data) => printer.data_matrix(&data)?,
-            Command::Aztec(data) => printer.aztec(&data)?
-            // // Command::BitImage(data) => { printer = printer.bit_image(&data)?; },
+            Command::Aztec(data) => printer.aztec(&data)?,
+            Command::SectionHeader(title) => {
+                let rule = "=".repeat(font_columns(config.paper_width_chars, font) / size_width as usize);
+                printer
+                    .justify(JustifyMode::CENTER)?
+                    .writeln(&rule)?
+                    .bold(true)?
+                    .writeln(&encode_text(&title, active_page))?
+                    .bold(false)?
+                    .writeln(&rule)?
+                    .justify(JustifyMode::LEFT)?
+            }
+            Command::Row { left, right } => {
+                let columns = font_columns(config.paper_width_chars, font) / size_width as usize;
+                printer.writeln(&encode_text(&layout_row(&left, &right, columns), active_page))?
+            }
+            Command::Divider(character) => {
+                let columns = font_columns(config.paper_width_chars, font) / size_width as usize;
+                printer.writeln(&encode_text(&divider_line(character, columns), active_page))?
+            }
+            Command::Color(color) => {
+                let n = match color {
+                    ReceiptColor::Black => 0,
+                    ReceiptColor::Red => 1,
+                };
+                printer.custom(&[0x1B, 0x72, n])?
+            }
+            Command::SetTabs(stops) => {
+                tabs = stops;
+                printer.custom(&tab_stops_command(&tabs))?
+            }
+            Command::Tab(_) => printer.custom(&[0x09])?,
+            Command::PrintMode { font: selected, bold, double_height, double_width, underline } => {
+                font = match selected {
+                    Some(Font::B) | Some(Font::C) => Font::B,
+                    _ => Font::A,
+                };
+                size_width = if double_width.unwrap_or(false) { 2 } else { 1 };
+                printer.custom(&[0x1B, 0x21, print_mode_byte(selected, bold, double_height, double_width, underline)])?
+            }
+            Command::ImageFile(image) => {
+                let path = resolve_image_path(image.path(), &config.image_dir)?;
+                match image {
+                    ImageSource::Path(_) => printer.bit_image(&path)?,
+                    ImageSource::WithOptions(params) => {
+                        let paper_dots = paper_width_dots(config.paper_width_chars);
+                        let bytes = prepare_image(
+                            &path,
+                            params.align.unwrap_or(JustifyMode::LEFT),
+                            params.max_width_dots.map_or(paper_dots, u32::from),
+                            params.dither.unwrap_or(false),
+                            paper_dots,
+                        )?;
+                        printer.bit_image_from_bytes_option(&bytes, BitImageOption::new(None, None, BitImageSize::Normal)?)?
+                    }
+                }
+            }
         };
     }
 
-    printer.print_cut()?;
+    if rotate {
+        printer.upside_down(false)?;
+    }
+    if !auto_cut || ends_with_cut || matches!(config.default_cut, CutType::None) {
+        printer.print()?;
+    } else {
+        if config.cut_feed_lines > 0 {
+            printer.feeds(config.cut_feed_lines)?;
+        }
+        match config.default_cut {
+            CutType::Partial => printer.partial_cut()?.print()?,
+            _ => printer.print_cut()?,
+        };
+    }
+    if config.flush_after_cut {
+        driver.drain()?;
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AppConfig;
+    use crate::print::print_receipt;
+    use crate::state::AppState;
+
+    /// The bytes `commands` produce on a `paper_width_chars` printer, with no
+    /// header, footer or cut around them.
+    async fn captured(commands: Vec<Command>, config: &PrinterConfig) -> Vec<u8> {
+        let dry_run = DryRunDriver::default();
+        let job = Commands {
+            commands,
+            auto_cut: Some(false),
+            encoding: None,
+            skip_header_footer: Some(true),
+            label: None,
+            rotate_180: None,
+        };
+        execute_commands(PrinterDriver::DryRun(dry_run.clone()), job, config).await.unwrap();
+        dry_run.captured()
+    }
+
+    fn contains(bytes: &[u8], sequence: &[u8]) -> bool {
+        bytes.windows(sequence.len()).any(|window| window == sequence)
+    }
+
+    async fn assert_section_header(width: usize, size_width: u8) {
+        let config = PrinterConfig { paper_width_chars: width, ..PrinterConfig::default() };
+        let commands = vec![Command::Size((size_width, 1)), Command::SectionHeader("GRILL".to_string())];
+        let bytes = captured(commands, &config).await;
+        let columns = width / size_width as usize;
+        let rule = format!("{}\n", "=".repeat(columns));
+        let mut expected = vec![0x1B, 0x61, 0x01];
+        expected.extend_from_slice(rule.as_bytes());
+        expected.extend_from_slice(&[0x1B, 0x45, 0x01]);
+        expected.extend_from_slice(b"GRILL\n");
+        expected.extend_from_slice(&[0x1B, 0x45, 0x00]);
+        expected.extend_from_slice(rule.as_bytes());
+        expected.extend_from_slice(&[0x1B, 0x61, 0x00]);
+        assert!(contains(&bytes, &expected), "no centred, bold header ruled to {} columns", columns);
+        assert!(!contains(&bytes, "=".repeat(columns + 1).as_bytes()));
+    }
+
+    #[tokio::test]
+    async fn auto_init_off_sends_no_init_between_jobs() {
+        let mut config = AppConfig::default();
+        config.printer.auto_init_per_job = false;
+        config.printer.open_drawer_after_print = Some(CashDrawer::Pin2);
+        let dry_run = DryRunDriver::default();
+        let state = AppState::new(Some(PrinterDriver::DryRun(dry_run.clone())), config);
+        for text in ["first", "second"] {
+            let job = format!(r#"{{"commands":[{{"command":"Writeln","parameters":"{}"}}]}}"#, text);
+            print_receipt(state.clone(), &job).await.unwrap();
+        }
+        let bytes = dry_run.captured();
+        assert!(contains(&bytes, b"second"), "the second job was captured");
+        assert!(contains(&bytes, &[0x1B, 0x70]), "the drawer was kicked");
+        assert!(!contains(&bytes, &[0x1B, 0x40]));
+    }
+
+    #[tokio::test]
+    async fn out_of_range_nv_logo_is_invalid_input() {
+        for (slot, mode) in [(0, 0), (1, 4)] {
+            let job = Commands {
+                commands: vec![Command::NvLogo { slot, mode }],
+                auto_cut: None,
+                encoding: None,
+                skip_header_footer: Some(true),
+                label: None,
+                rotate_180: None,
+            };
+            let dry_run = DryRunDriver::default();
+            let printed = execute_commands(PrinterDriver::DryRun(dry_run.clone()), job, &PrinterConfig::default()).await;
+            assert!(matches!(printed, Err(PrinterError::Input(_))), "slot {} mode {} was accepted", slot, mode);
+            assert!(!contains(&dry_run.captured(), &[0x1C, 0x70]));
+        }
+    }
+
+    #[tokio::test]
+    async fn section_header_fills_58mm_paper() {
+        assert_section_header(32, 1).await;
+    }
+
+    #[tokio::test]
+    async fn section_header_fills_80mm_paper() {
+        assert_section_header(48, 1).await;
+    }
+
+    #[tokio::test]
+    async fn section_header_fills_the_line_at_double_width() {
+        assert_section_header(48, 2).await;
+    }
+}
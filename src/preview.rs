@@ -0,0 +1,301 @@
+use escpos::utils::{Font, JustifyMode, PageCode};
+
+use crate::gs1;
+use crate::models::{divider_line, fit_to_page_code, format_datetime, font_columns, layout_row, Command, Commands, ImageSource};
+
+const CUT_MARKER: &str = "- - - - - - cut - - - - - -";
+/// Tab stops a printer uses until `SetTabs`: every 8 columns.
+const DEFAULT_TAB_WIDTH: usize = 8;
+/// Glyph widths in dots, for turning print positions into columns.
+const FONT_A_DOTS: usize = 12;
+const FONT_B_DOTS: usize = 9;
+
+#[derive(Clone, Copy)]
+enum Align {
+    Left,
+    Center,
+    Right,
+}
+
+impl From<&JustifyMode> for Align {
+    fn from(mode: &JustifyMode) -> Align {
+        match mode {
+            JustifyMode::LEFT => Align::Left,
+            JustifyMode::CENTER => Align::Center,
+            JustifyMode::RIGHT => Align::Right,
+        }
+    }
+}
+
+/// Lays a command stream out as plain text lines, one per printed line. Only
+/// layout is modelled (justification, feeds, cuts, placeholders for codes);
+/// styling like bold or underline has no text equivalent and is dropped.
+struct TextRenderer {
+    width: usize,
+    size_width: usize,
+    font: Font,
+    /// The job's encoding, which Init and Reset go back to.
+    encoding: Option<PageCode>,
+    page_code: Option<PageCode>,
+    align: Align,
+    tabs: Vec<usize>,
+    current: String,
+    lines: Vec<String>,
+}
+
+impl TextRenderer {
+    fn new(width: usize, encoding: Option<PageCode>) -> TextRenderer {
+        TextRenderer {
+            width,
+            size_width: 1,
+            font: Font::A,
+            encoding,
+            page_code: encoding,
+            align: Align::Left,
+            tabs: (1..=width / DEFAULT_TAB_WIDTH).map(|stop| stop * DEFAULT_TAB_WIDTH).collect(),
+            current: String::new(),
+            lines: Vec::new(),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.align = Align::Left;
+        self.size_width = 1;
+        self.font = Font::A;
+        self.page_code = self.encoding;
+    }
+
+    /// Text as the active code page prints it, with `?` for what it lacks.
+    fn fit(&self, text: &str) -> String {
+        fit_to_page_code(text, self.page_code).0
+    }
+
+    /// Characters that fit on a line in the current font.
+    fn columns(&self) -> usize {
+        font_columns(self.width, self.font)
+    }
+
+    /// Double-width text takes `size_width` columns per glyph on paper; the
+    /// preview shows that by spacing the glyphs out.
+    fn stretch(&self, text: &str) -> String {
+        if self.size_width <= 1 {
+            return text.to_string();
+        }
+        let gap = " ".repeat(self.size_width - 1);
+        text.chars().map(|c| format!("{}{}", c, gap)).collect()
+    }
+
+    /// Text longer than the line wraps onto the next one, and the printer
+    /// justifies every wrapped piece separately.
+    fn push_line(&mut self, text: &str) {
+        let text: Vec<char> = self.stretch(text).chars().collect();
+        let columns = self.columns().max(1);
+        if text.is_empty() {
+            self.lines.push(String::new());
+            return;
+        }
+        for piece in text.chunks(columns) {
+            let padding = match self.align {
+                Align::Left => 0,
+                Align::Center => (columns - piece.len()) / 2,
+                Align::Right => columns - piece.len(),
+            };
+            let piece: String = piece.iter().collect();
+            self.lines.push(format!("{}{}", " ".repeat(padding), piece).trim_end().to_string());
+        }
+    }
+
+    fn write(&mut self, text: &str) {
+        let mut parts = text.split('\n');
+        if let Some(first) = parts.next() {
+            self.current.push_str(first);
+        }
+        for part in parts {
+            self.flush();
+            self.current.push_str(part);
+        }
+    }
+
+    /// Ends the pending line, even when nothing has been written on it.
+    fn flush(&mut self) {
+        let line = std::mem::take(&mut self.current);
+        self.push_line(&line);
+    }
+
+    fn flush_pending(&mut self) {
+        if !self.current.is_empty() {
+            self.flush();
+        }
+    }
+
+    fn feed(&mut self, lines: u8) {
+        let mut remaining = lines;
+        if !self.current.is_empty() {
+            self.flush();
+            remaining = remaining.saturating_sub(1);
+        }
+        for _ in 0..remaining {
+            self.lines.push(String::new());
+        }
+    }
+
+    /// Pads to the next stop. Past the last one the printer ignores HT.
+    fn tab(&mut self) {
+        let column = self.current.chars().count();
+        if let Some(stop) = self.tabs.iter().find(|stop| **stop > column) {
+            self.current.push_str(&" ".repeat(stop - column));
+        }
+    }
+
+    /// Dots one character of the pending line takes at the current font and size.
+    fn char_dots(&self) -> usize {
+        let glyph = match self.font {
+            Font::A => FONT_A_DOTS,
+            Font::B | Font::C => FONT_B_DOTS,
+        };
+        glyph * self.size_width
+    }
+
+    /// Puts the pending line at the column holding `dots`, padding it out or
+    /// cutting it back. Positions between columns round down.
+    fn move_to(&mut self, dots: usize) {
+        let column = dots / self.char_dots();
+        let current = self.current.chars().count();
+        if column > current {
+            self.current.push_str(&" ".repeat(column - current));
+        } else {
+            self.current = self.current.chars().take(column).collect();
+        }
+    }
+
+    fn placeholder(&mut self, kind: &str, data: &str) {
+        self.flush_pending();
+        self.push_line(&format!("[{}: {}]", kind, data));
+    }
+
+    fn cut(&mut self) {
+        self.flush_pending();
+        self.lines.push(CUT_MARKER.to_string());
+    }
+
+    fn apply(&mut self, command: &Command) {
+        match command {
+            Command::Init(_) | Command::Reset(_) => self.reset(),
+            Command::Cut(_) | Command::PartialCut(_) | Command::PrintCut(_) => self.cut(),
+            Command::Justify(mode) => self.align = Align::from(mode),
+            Command::Size((width, _)) => self.size_width = (*width).max(1) as usize,
+            Command::ResetSize(_) => self.size_width = 1,
+            Command::Font(font) => self.font = *font,
+            Command::PageCode(page_code) => self.page_code = Some(*page_code),
+            Command::Feed(_) => self.feed(1),
+            Command::Feeds(lines) => self.feed(*lines),
+            Command::Write(text) => self.write(&self.fit(text)),
+            Command::Writeln(text) => {
+                self.write(&self.fit(text));
+                self.flush();
+            }
+            Command::DateTime(format) => match format_datetime(format.as_deref()) {
+                Ok(text) => {
+                    self.write(&self.fit(&text));
+                    self.flush();
+                }
+                Err(_) => self.placeholder("DATETIME", format.as_deref().unwrap_or_default()),
+            },
+            Command::NvLogo { slot, .. } => {
+                self.flush_pending();
+                self.push_line(&format!("[NV LOGO #{}]", slot));
+            }
+            Command::PaperStatus(_) => self.placeholder("PAPER", "OK/LOW/OUT"),
+            Command::Ean13(code) => self.placeholder("EAN13", code.data()),
+            Command::Ean8(code) => self.placeholder("EAN8", code.data()),
+            Command::Upca(code) => self.placeholder("UPC-A", code.data()),
+            Command::Upce(code) => self.placeholder("UPC-E", code.data()),
+            Command::Code39(code) => self.placeholder("CODE39", code.data()),
+            Command::Codabar(code) => self.placeholder("CODABAR", code.data()),
+            Command::Itf(code) => self.placeholder("ITF", code.data()),
+            Command::Gs1128(text) => match gs1::parse(text) {
+                Ok(elements) => self.placeholder("GS1-128", &gs1::human_readable(&elements)),
+                Err(_) => self.placeholder("GS1-128", text),
+            },
+            Command::Qrcode(code) => match code.size_and_level() {
+                Some((size, level)) => self.placeholder(&format!("QR x{} {:?}", size, level), code.data()),
+                None => self.placeholder("QR", code.data()),
+            },
+            Command::GS1Databar2d(data) => self.placeholder("GS1 DATABAR", data),
+            Command::Pdf417(data) => self.placeholder("PDF417", data),
+            Command::MaxiCode(data) => self.placeholder("MAXICODE", data),
+            Command::DataMatrix(data) => self.placeholder("DATAMATRIX", data),
+            Command::Aztec(data) => self.placeholder("AZTEC", data),
+            Command::ImageFile(ImageSource::Path(path)) => self.placeholder("IMAGE", path),
+            Command::ImageFile(ImageSource::WithOptions(params)) => {
+                let previous = self.align;
+                self.align = params.align.as_ref().map_or(Align::Left, Align::from);
+                let kind = match params.max_width_dots {
+                    Some(dots) => format!("IMAGE <= {} dots", dots),
+                    None => "IMAGE".to_string(),
+                };
+                self.placeholder(&kind, &params.path);
+                self.align = previous;
+            }
+            Command::SetTabs(stops) => self.tabs = stops.iter().map(|stop| *stop as usize).collect(),
+            Command::Tab(_) => self.tab(),
+            Command::AbsolutePosition(dots) => self.move_to(*dots as usize),
+            Command::RelativePosition(dots) => {
+                let position = (self.current.chars().count() * self.char_dots()) as i64 + *dots as i64;
+                self.move_to(position.max(0) as usize);
+            }
+            Command::PrintMode { font, double_width, .. } => {
+                self.font = match font {
+                    Some(Font::B) | Some(Font::C) => Font::B,
+                    _ => Font::A,
+                };
+                self.size_width = if double_width.unwrap_or(false) { 2 } else { 1 };
+            }
+            Command::SectionHeader(title) => {
+                self.flush_pending();
+                // Bold has no text form, so the title is upper-cased to stand out.
+                let rule = self.stretch(&"=".repeat(self.columns() / self.size_width));
+                let rule = rule.trim_end().to_string();
+                self.lines.push(rule.clone());
+                self.align = Align::Center;
+                self.push_line(&self.fit(&title.to_uppercase()));
+                self.lines.push(rule);
+                // Printing resets to left justification after the header too.
+                self.align = Align::Left;
+            }
+            Command::Divider(character) => {
+                self.flush_pending();
+                let columns = self.columns() / self.size_width;
+                let line = self.stretch(&self.fit(&divider_line(*character, columns)));
+                self.lines.push(line.trim_end().to_string());
+            }
+            Command::Row { left, right } => {
+                self.flush_pending();
+                let columns = self.columns() / self.size_width;
+                let line = self.stretch(&self.fit(&layout_row(left, right, columns)));
+                self.lines.push(line.trim_end().to_string());
+            }
+            _ => {}
+        }
+    }
+
+    fn finish(mut self) -> Vec<String> {
+        self.flush_pending();
+        self.lines
+    }
+}
+
+/// Renders `commands` the way the receipt should come out, without any USB
+/// access. The trailing cut that printing appends is not shown. A rotated
+/// job is shown as the customer reads it, last line first.
+pub fn render_preview(commands: &Commands, width: usize) -> Vec<String> {
+    let mut renderer = TextRenderer::new(width.max(1), commands.encoding);
+    for command in &commands.commands {
+        renderer.apply(command);
+    }
+    let mut lines = renderer.finish();
+    if commands.rotate_180.unwrap_or(false) {
+        lines.reverse();
+    }
+    lines
+}
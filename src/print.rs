@@ -1,50 +1,205 @@
 use std::future::Future;
+use std::sync::Arc;
 use std::time::Duration;
+use escpos::driver::Driver;
 use escpos::errors::PrinterError;
-use escpos::{driver::UsbDriver, printer::Printer, utils::*};
+use escpos::{printer::Printer, utils::*};
 use tokio::time::sleep;
 
-use crate::models::{execute_commands, parse_json, Command, Commands, PrinterTestSchema};
+use crate::config::{Connection, PrinterConfig, STANDARD_PRESET};
+use crate::driver::PrinterDriver;
+use crate::error::AppError;
+use crate::models::{execute_commands, parse_json, Command, Commands, DeviceStatus, PrintedOn, PrinterStatus, PrinterTestSchema};
+use crate::state::AppState;
+use crate::usb;
 
-pub async fn initialize_device() -> UsbDriver {
+const SELF_TEST_LINE: &str = "REIKA self-test: printer OK";
+
+/// Opens the printer, waiting `retry_delay_ms` between tries (doubling with
+/// `retry_backoff`). Gives up with `None` after `connect_attempts` tries; 0
+/// keeps trying until it appears.
+pub async fn initialize_device(config: &PrinterConfig) -> Option<PrinterDriver> {
+    if config.selected_preset().is_none() && config.preset != STANDARD_PRESET {
+        println!("Unknown printer preset {:?}, using {}.", config.preset, STANDARD_PRESET);
+    }
+    let mut attempts = 0;
+    let mut delay = Duration::from_millis(config.retry_delay_ms);
+    let max_delay = Duration::from_millis(config.retry_max_delay_ms.max(config.retry_delay_ms));
     loop {
-        match UsbDriver::open(0x0483, 0x5840, None) {
+        match PrinterDriver::open(config) {
             Ok(driver) => {
-                return driver;
+                return Some(driver);
             },
-            _ => {
-                println!("Failed to open the USB driver. Retrying in 5 seconds");
-                sleep(Duration::from_secs(5)).await;
+            Err(e) => {
+                attempts += 1;
+                if config.connect_attempts > 0 && attempts >= config.connect_attempts {
+                    println!("Failed to open the printer after {} attempts: {}", attempts, e);
+                    return None;
+                }
+                match config.connection {
+                    Connection::Usb => println!("Failed to open the USB driver. Retrying in {:?}", delay),
+                    Connection::Serial => println!("Failed to open serial port {:?}: {}. Retrying in {:?}", config.serial_port, e, delay),
+                    Connection::Spooler => println!("Failed to open Windows printer {:?}: {}. Retrying in {:?}", config.spooler_printer, e, delay),
+                    Connection::File => println!("Failed to open capture file {:?}: {}. Retrying in {:?}", config.capture_path, e, delay),
+                }
+                sleep(delay).await;
+                if config.retry_backoff {
+                    delay = (delay * 2).min(max_delay);
+                }
+            }
+        }
+    }
+}
+
+/// Cheap, non-printing check that the configured printer is plugged in.
+pub fn check_connection(config: &PrinterConfig) -> bool {
+    match config.connection {
+        Connection::Usb => usb::is_present(config.resolved_vendor_id(), config.resolved_product_id()),
+        Connection::Serial => serialport::available_ports()
+            .map(|ports| ports.iter().any(|port| port.port_name == config.serial_port))
+            .unwrap_or(false),
+        #[cfg(windows)]
+        Connection::Spooler => crate::driver::SpoolerDriver::open(&config.spooler_printer).is_ok(),
+        #[cfg(not(windows))]
+        Connection::Spooler => false,
+        Connection::File => std::path::Path::new(&config.capture_path)
+            .parent()
+            .is_some_and(|dir| dir.as_os_str().is_empty() || dir.is_dir()),
+    }
+}
+
+/// Background task that keeps the online flag current between prints using
+/// `check_connection`, and the paper and cover flags with a status request.
+/// Like every health check it never advances paper. It also opens the
+/// printer once it appears if the service started without one.
+pub async fn poll_health(state: AppState, interval: Duration) {
+    loop {
+        sleep(interval).await;
+        // A running reconnect owns the status until it finishes.
+        if state.in_maintenance() || state.status() == PrinterStatus::Reconnecting {
+            continue;
+        }
+        let present = check_connection(&state.config.printer);
+        if present && state.driver().is_none() {
+            match PrinterDriver::open(&state.config.printer) {
+                Ok(driver) => {
+                    println!("Printer found, connected to it.");
+                    state.set_driver(driver);
+                },
+                Err(e) => {
+                    println!("Printer is present but could not be opened: {}", e);
+                    continue;
+                }
+            }
+        }
+        state.update_status(if present { PrinterStatus::Online } else { PrinterStatus::Offline });
+        if let Some(backup) = state.config.printer.backup_config() {
+            state.set_backup_status(if check_connection(&backup) { PrinterStatus::Online } else { PrinterStatus::Offline });
+        }
+        if !present {
+            state.set_device_status(DeviceStatus::default());
+            continue;
+        }
+        // Without sensors to read, being present is all there is to know.
+        if !state.driver().is_some_and(|driver| driver.answers_status()) {
+            continue;
+        }
+        // A running job means the printer is busy, not broken; the sensors
+        // are read again on the next tick instead of holding the job up.
+        if let Some(_printing) = state.try_lock_printer() {
+            state.set_device_status(query_device_status(&state).await);
+        }
+    }
+}
+
+/// Background task that sends the printer a status request every `interval`
+/// so the connection never idles long enough to sleep. It gives way to any
+/// job holding the printer, and a failure is left for the next print to
+/// reconnect.
+pub async fn keep_alive(state: AppState, interval: Duration) {
+    loop {
+        sleep(interval).await;
+        if state.in_maintenance() || !state.is_online() {
+            continue;
+        }
+        let _printing = match state.try_lock_printer() {
+            Some(guard) => guard,
+            None => continue,
+        };
+        // The capture file and the spooler never idle out, and writing to
+        // them would only record or print the keepalive.
+        if let Some(driver) = state.driver().filter(PrinterDriver::answers_status) {
+            if let Err(e) = liveness_probe(driver).await {
+                println!("Keepalive to the printer failed: {}", e);
             }
         }
     }
 }
 
-async fn reconnect_device(driver: &mut UsbDriver) {
+async fn reconnect_device(state: &AppState) {
     println!("Attempting to reconnect to the USB device...");
-    *driver = initialize_device().await;
-    println!("Reconnected to the USB device.");
+    state.metrics.record_reconnect();
+    state.update_status(PrinterStatus::Reconnecting);
+    match initialize_device(&state.config.printer).await {
+        Some(driver) => {
+            state.set_driver(driver);
+            println!("Reconnected to the USB device.");
+        },
+        None => state.update_status(PrinterStatus::Offline),
+    }
+}
+
+async fn ensure_driver<F, Fut, T>(state: &AppState, f: F) -> Result<T, PrinterError>
+where
+    F: Fn(PrinterDriver) -> Fut,
+    Fut: Future<Output = Result<T, PrinterError>>,
+{
+    let _job = state.begin_job();
+    let _printing = state.lock_printer().await;
+    with_driver(state, f).await
 }
 
-async fn ensure_driver<F, Fut, T>(mut driver: UsbDriver, f: F) -> Result<T, PrinterError>
+/// The retry loop of `ensure_driver`, for callers already holding the printer
+/// lock across several jobs.
+async fn with_driver<F, Fut, T>(state: &AppState, f: F) -> Result<T, PrinterError>
 where
-    F: Fn(UsbDriver) -> Fut,
+    F: Fn(PrinterDriver) -> Fut,
     Fut: Future<Output = Result<T, PrinterError>>,
 {
     loop {
-        let fut = f(driver.clone());
-        match fut.await {
-            Ok(result) => return Ok(result),
-            Err(_) => {
-                reconnect_device(&mut driver).await;
+        let driver = match state.driver() {
+            Some(driver) => driver,
+            None if state.in_maintenance() => {
+                return Err(PrinterError::Io("the printer is not connected".to_string()));
+            },
+            None => {
+                reconnect_device(state).await;
+                continue;
+            }
+        };
+        match f(driver).await {
+            Ok(result) => {
+                state.update_status(PrinterStatus::Online);
+                return Ok(result);
+            },
+            // Bad input fails the same way on any connection, so reconnecting
+            // would only spin until the job timeout.
+            Err(e @ PrinterError::Input(_)) => return Err(e),
+            Err(e) => {
+                if state.in_maintenance() {
+                    state.update_status(PrinterStatus::Offline);
+                    println!("Maintenance mode is on, not reconnecting.");
+                    return Err(e);
+                }
+                reconnect_device(state).await;
             }
         }
     }
 }
 
-async fn initial_attempt<F, Fut>(driver: UsbDriver, f: F) -> bool
+async fn initial_attempt<F, Fut>(driver: PrinterDriver, f: F) -> bool
 where
-    F: Fn(UsbDriver) -> Fut,
+    F: Fn(PrinterDriver) -> Fut,
     Fut: Future<Output = Result<(), PrinterError>>,
 {
     match f(driver).await {
@@ -53,28 +208,47 @@ where
     }
 }
 
-async fn retry_attempt<F, Fut>(mut driver: UsbDriver, f: F) -> bool
+async fn retry_attempt<F, Fut>(state: &AppState, f: F) -> bool
 where
-    F: Fn(UsbDriver) -> Fut,
+    F: Fn(PrinterDriver) -> Fut,
     Fut: Future<Output = Result<(), PrinterError>>,
 {
     loop {
-        let fut = f(driver.clone());
-        match fut.await {
-            Ok(_) => return true,
+        let driver = match state.driver() {
+            Some(driver) => driver,
+            None if state.in_maintenance() => return false,
+            None => {
+                reconnect_device(state).await;
+                continue;
+            }
+        };
+        match f(driver).await {
+            Ok(_) => {
+                state.update_status(PrinterStatus::Online);
+                return true;
+            },
+            Err(PrinterError::Input(_)) => return false,
             Err(_) => {
-                reconnect_device(&mut driver).await;
+                if state.in_maintenance() {
+                    state.update_status(PrinterStatus::Offline);
+                    return false;
+                }
+                reconnect_device(state).await;
             }
         }
     }
 }
 
 pub async fn handle_test_print(
-    driver: UsbDriver,
+    state: AppState,
     print_request: PrinterTestSchema,
-) -> Result<(), PrinterError> {
-    ensure_driver(driver, move |d| {
+) -> Result<(), AppError> {
+    let config = state.config.clone();
+    // Neither job ends with a cut of its own, so the end-of-job cut follows
+    // `default_cut` and a partial-cut-only printer passes the test too.
+    let job = ensure_driver(&state, move |d| {
         let print_request = print_request.clone();
+        let config = config.clone();
         async move {
             if *print_request.test_page() {
                 let test_commands = Commands {
@@ -93,51 +267,382 @@ pub async fn handle_test_print(
                         Command::Underline(UnderlineMode::None),
                         Command::Size((2, 3)),
                         Command::Writeln("Hello world - Normal".to_string()),
-                        Command::PrintCut(None),
                     ],
+                    auto_cut: None,
+                    encoding: None,
+                    skip_header_footer: Some(true),
+                    label: None,
+                    rotate_180: None,
                 };
-                execute_commands(d.clone(), test_commands).await?;
+                execute_commands(d.clone(), test_commands, &config.printer).await?;
             }
 
             if !print_request.test_line().is_empty() {
                 let line_commands = Commands {
-                    commands: vec![Command::Writeln(print_request.test_line().to_string()), Command::PrintCut(None)],
+                    commands: vec![Command::Writeln(print_request.test_line().to_string())],
+                    auto_cut: None,
+                    encoding: None,
+                    skip_header_footer: Some(true),
+                    label: None,
+                    rotate_180: None,
                 };
-                execute_commands(d, line_commands).await?;
+                execute_commands(d, line_commands, &config.printer).await?;
             }
             Ok(())
         }
-    }).await
+    });
+    with_job_timeout(&state, job).await
+}
+
+/// Prints one fixed line at startup and logs how it went.
+pub async fn self_test(state: AppState) {
+    println!("Running the startup self-test...");
+    match handle_test_print(state, PrinterTestSchema::line(SELF_TEST_LINE)).await {
+        Ok(_) => println!("Startup self-test printed."),
+        Err(e) => println!("Startup self-test failed: {}", e),
+    }
+}
+
+/// Runs a job under `job_timeout_secs`. The escpos calls block, so the limit
+/// is enforced at the awaits between retries; that is where a wedged printer
+/// keeps a job stuck.
+async fn with_job_timeout<T, Fut>(state: &AppState, job: Fut) -> Result<T, AppError>
+where
+    Fut: Future<Output = Result<T, PrinterError>>,
+{
+    let secs = state.config.printer.job_timeout_secs;
+    if secs == 0 {
+        return job.await.map_err(AppError::from);
+    }
+    let limit = Duration::from_secs(secs);
+    match tokio::time::timeout(limit, job).await {
+        Ok(result) => result.map_err(AppError::from),
+        Err(_) => {
+            println!("Print job timed out after {:?}, giving up.", limit);
+            if state.status() == PrinterStatus::Reconnecting {
+                state.update_status(PrinterStatus::Offline);
+            }
+            Err(AppError::Timeout(limit))
+        }
+    }
+}
+
+/// Sends a caller-built ESC/POS stream as is. It goes through the same
+/// lock, retries and timeout as a `/print` job, just without the commands.
+pub async fn print_raw(state: &AppState, data: Vec<u8>) -> Result<usize, AppError> {
+    let data = Arc::new(data);
+    let job = ensure_driver(state, move |d| {
+        let data = data.clone();
+        async move {
+            d.start_job()?;
+            d.write(&data)?;
+            d.flush()?;
+            Ok(data.len())
+        }
+    });
+    let written = with_job_timeout(state, job).await;
+    match &written {
+        Ok(_) => state.metrics.record_print(),
+        Err(_) => state.metrics.record_print_failure(),
+    }
+    written
+}
+
+/// One try at a job on the backup, given its `backup_config`, over a
+/// connection opened just for it the same way the main printer's is.
+async fn print_on_backup(backup: &PrinterConfig, json_commands: &str) -> Result<(), PrinterError> {
+    let driver = PrinterDriver::open(backup)?;
+    let commands = parse_json(json_commands)?;
+    execute_commands(driver, commands, backup).await
+}
+
+/// Prints the job on the main printer, or on the backup if that fails and
+/// `fallback_on_failure` is set, and says which one it came out of. Opens the
+/// drawer afterwards if `open_drawer_after_print` asks for it.
+pub async fn print_receipt(state: AppState, json_commands: &str) -> Result<PrintedOn, AppError> {
+    let printed_on = print_without_drawer(state.clone(), json_commands).await?;
+    // Kicked separately from the job so a drawer failure retries only the kick, never the receipt.
+    // The receipt is out by now, so failing the request would only get it
+    // printed again by a client that retries.
+    // The drawer hangs off the main printer, so a backup print doesn't kick it.
+    if let (PrintedOn::Main, Some(pin)) = (printed_on, state.config.printer.open_drawer_after_print) {
+        if let Err(e) = open_cash_drawer(&state, pin).await {
+            println!("Printed, but failed to open the cash drawer: {}", e);
+        }
+    }
+    Ok(printed_on)
 }
 
-pub async fn print_receipt(driver: UsbDriver, json_commands: &str) -> Result<(), PrinterError> {
+/// [`print_receipt`] without the drawer kick, for spooled jobs: those print
+/// long after the sale, when an opening drawer would be nobody's.
+pub async fn print_without_drawer(state: AppState, json_commands: &str) -> Result<PrintedOn, AppError> {
     println!("Printing the following: {:#?}", json_commands);
-    ensure_driver(driver, move |d| {
+    let config = state.config.clone();
+    let job = ensure_driver(&state, move |d| {
         let json_commands = json_commands.to_string();
+        let config = config.clone();
         async move {
             let commands = parse_json(&json_commands)?;
-            execute_commands(d, commands).await?;
+            execute_commands(d, commands, &config.printer).await?;
             Ok(())
         }
-    }).await.map_err(|e| PrinterError::Io(e.to_string())) // Manually convert to PrinterError here
+    });
+    match with_job_timeout(&state, job).await {
+        Ok(_) => state.metrics.record_print(),
+        Err(e) => {
+            // Bad input would fail the same way on the backup.
+            let printer_fault = !matches!(e, AppError::Printer(PrinterError::Input(_)));
+            let backup = if printer_fault && state.config.printer.fallback_on_failure {
+                state.config.printer.backup_config()
+            } else {
+                None
+            };
+            if let Some(backup) = backup {
+                println!("Print failed on the main printer ({}), trying the {:?} backup.", e, backup.connection);
+                // Still under the printer lock, so jobs reach the backup one at a time too.
+                let backup_job = async {
+                    let _job = state.begin_job();
+                    let _printing = state.lock_printer().await;
+                    print_on_backup(&backup, json_commands).await
+                };
+                match with_job_timeout(&state, backup_job).await {
+                    Ok(_) => {
+                        println!("Printed on the {:?} backup printer.", backup.connection);
+                        state.set_backup_status(PrinterStatus::Online);
+                        state.metrics.record_print();
+                        return Ok(PrintedOn::Backup);
+                    }
+                    Err(backup_error) => {
+                        println!("The backup printer failed too: {}", backup_error);
+                        state.set_backup_status(PrinterStatus::Offline);
+                    }
+                }
+            }
+            state.metrics.record_print_failure();
+            return Err(e);
+        }
+    }
+    Ok(PrintedOn::Main)
+}
+
+/// Prints `jobs` in order under one hold of the printer lock, so no other
+/// job lands between them. A failed job doesn't stop the ones after it.
+pub async fn print_batch(state: &AppState, jobs: Vec<Commands>, separator: Option<Vec<Command>>) -> Vec<Result<(), AppError>> {
+    let _job = state.begin_job();
+    let _printing = state.lock_printer().await;
+    let count = jobs.len();
+    let mut results = Vec::with_capacity(count);
+    for (index, commands) in jobs.into_iter().enumerate() {
+        let printed = run_locked(state, commands).await;
+        match &printed {
+            Ok(_) => state.metrics.record_print(),
+            Err(_) => state.metrics.record_print_failure(),
+        }
+        results.push(printed);
+        if let (Some(separator), true) = (&separator, index + 1 < count) {
+            let separator = Commands {
+                commands: separator.clone(),
+                auto_cut: Some(false),
+                encoding: None,
+                skip_header_footer: Some(true),
+                label: None,
+                rotate_180: None,
+            };
+            if let Err(e) = run_locked(state, separator).await {
+                println!("Failed to print the batch separator: {}", e);
+            }
+        }
+    }
+
+    if let (Some(pin), true) = (state.config.printer.open_drawer_after_print, results.iter().any(Result::is_ok)) {
+        let init = state.config.printer.auto_init_per_job;
+        if let Err(e) = with_job_timeout(state, with_driver(state, move |d| kick_drawer(d, pin, init))).await {
+            println!("Failed to open the cash drawer after the batch: {}", e);
+        }
+    }
+    results
+}
+
+/// One job of a batch, with the printer lock already held.
+async fn run_locked(state: &AppState, commands: Commands) -> Result<(), AppError> {
+    let config = state.config.clone();
+    let job = with_driver(state, move |d| {
+        let commands = commands.clone();
+        let config = config.clone();
+        async move { execute_commands(d, commands, &config.printer).await }
+    });
+    with_job_timeout(state, job).await
 }
 
-pub async fn is_device_connected(driver: UsbDriver) -> bool {
-    if !initial_attempt(driver.clone(), |d| async move {
-        let mut printer = Printer::new(d.clone(), Protocol::default(), None);
+/// `init` follows `auto_init_per_job`, so a client managing the printer's
+/// state itself doesn't have it reset by a drawer kick either.
+async fn kick_drawer(d: PrinterDriver, pin: CashDrawer, init: bool) -> Result<(), PrinterError> {
+    let mut printer = Printer::new(d, Protocol::default(), None);
+    if init {
         printer.init()?;
-        printer.smoothing(true)?;
-        printer.print_cut()?;
+    }
+    printer.cash_drawer(pin)?;
+    printer.print()?;
+    Ok(())
+}
+
+pub async fn open_cash_drawer(state: &AppState, pin: CashDrawer) -> Result<(), AppError> {
+    let init = state.config.printer.auto_init_per_job;
+    with_job_timeout(state, ensure_driver(state, move |d| kick_drawer(d, pin, init))).await
+}
+
+/// Sends `DLE EOT n` and reads the single status byte back. `None` when the
+/// printer doesn't answer, which plenty of budget models never do, and
+/// without writing anything over a connection that can't answer at all.
+pub fn transmit_status(driver: &PrinterDriver, n: u8) -> Option<u8> {
+    if !driver.answers_status() {
+        return None;
+    }
+    driver.write(&[0x10, 0x04, n]).ok()?;
+    let mut response = [0u8; 1];
+    match driver.read(&mut response) {
+        Ok(1) => Some(response[0]),
+        _ => None,
+    }
+}
+
+/// Runs a status exchange on the blocking pool. Its read waits out the
+/// driver's timeout on a printer that never answers, which would otherwise
+/// hold up the runtime thread and every request with it.
+pub(crate) async fn blocking_exchange<T, F>(driver: PrinterDriver, exchange: F) -> Result<T, PrinterError>
+where
+    F: FnOnce(&PrinterDriver) -> T + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(move || exchange(&driver))
+        .await
+        .map_err(|e| PrinterError::Io(e.to_string()))
+}
+
+/// Reads the cover and paper sensors. Never prints, feeds or cuts. The
+/// caller holds the printer lock; a printer that stops answering is given up
+/// on after `job_timeout_secs` and reads as all clear.
+pub async fn query_device_status(state: &AppState) -> DeviceStatus {
+    let _job = state.begin_job();
+    let driver = match state.driver() {
+        Some(driver) => driver,
+        None => return DeviceStatus::default(),
+    };
+    // n = 2 is the offline cause (bit 2 cover open, bit 5 stopped on paper end),
+    // n = 4 the roll sensor (bits 2 and 3 set when it's nearly out, 5 and 6
+    // once the paper has run out).
+    let exchange = blocking_exchange(driver, |driver| (transmit_status(driver, 2), transmit_status(driver, 4)));
+    let (offline, paper) = with_job_timeout(state, exchange).await.unwrap_or((None, None));
+    DeviceStatus {
+        paper_out: offline.is_some_and(|status| status & 0x20 != 0) || paper.is_some_and(|status| status & 0x60 != 0),
+        paper_low: paper.is_some_and(|status| status & 0x0C != 0),
+        cover_open: offline.is_some_and(|status| status & 0x04 != 0),
+    }
+}
+
+/// Asks for the printer status (DLE EOT 1) and drains the answer, if any.
+/// Nothing is printed, fed or cut; a printer that never answers still counts
+/// as reachable once the request was written. A connection that can't answer
+/// counts as reachable once it is open, and is sent nothing.
+async fn liveness_probe(d: PrinterDriver) -> Result<(), PrinterError> {
+    if !d.answers_status() {
+        return Ok(());
+    }
+    blocking_exchange(d, |d| -> Result<(), PrinterError> {
+        d.write(&[0x10, 0x04, 0x01])?;
+        let mut response = [0u8; 1];
+        let _ = d.read(&mut response);
         Ok(())
-    }).await {
-        retry_attempt(driver, |d| async move {
-            let mut printer = Printer::new(d.clone(), Protocol::default(), None);
-            printer.init()?;
-            printer.smoothing(true)?;
-            printer.print_cut()?;
-            Ok(())
-        }).await
-    } else {
-        true
+    })
+    .await?
+}
+
+pub async fn is_device_connected(state: AppState) -> bool {
+    // A probe would end up in the capture file or as a spooler document, so
+    // those connections are only checked for presence.
+    if state.driver().is_some_and(|driver| !driver.answers_status()) {
+        let present = check_connection(&state.config.printer);
+        state.update_status(if present { PrinterStatus::Online } else { PrinterStatus::Offline });
+        return present;
+    }
+    let probe = async {
+        let _job = state.begin_job();
+        let _printing = state.lock_printer().await;
+        let connected = match state.driver() {
+            Some(driver) => initial_attempt(driver, liveness_probe).await,
+            None => false,
+        };
+        if !connected {
+            Ok::<_, PrinterError>(retry_attempt(&state, liveness_probe).await)
+        } else {
+            state.update_status(PrinterStatus::Online);
+            Ok(true)
+        }
+    };
+    with_job_timeout(&state, probe).await.unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AppConfig;
+    use crate::driver::{DryRunDriver, FileDriver};
+
+    const DRAWER_KICK: [u8; 2] = [0x1B, 0x70];
+    const CUT: [u8; 2] = [0x1D, 0x56];
+
+    fn drawer_state() -> (AppState, DryRunDriver) {
+        let mut config = AppConfig::default();
+        config.printer.open_drawer_after_print = Some(CashDrawer::Pin2);
+        let dry_run = DryRunDriver::default();
+        (AppState::new(Some(PrinterDriver::DryRun(dry_run.clone())), config), dry_run)
+    }
+
+    fn position(bytes: &[u8], sequence: &[u8]) -> Option<usize> {
+        bytes.windows(sequence.len()).position(|window| window == sequence)
+    }
+
+    #[tokio::test]
+    async fn drawer_opens_after_the_cut() {
+        let (state, dry_run) = drawer_state();
+        print_receipt(state, r#"{"commands":[{"command":"Writeln","parameters":"TOTAL 1.00"}]}"#).await.unwrap();
+        let captured = dry_run.captured();
+        let cut = position(&captured, &CUT).expect("the receipt is cut");
+        let kick = position(&captured, &DRAWER_KICK).expect("the drawer is kicked");
+        assert!(kick > cut);
+    }
+
+    #[tokio::test]
+    async fn drawer_stays_shut_when_the_print_fails() {
+        let (state, dry_run) = drawer_state();
+        let printed = print_receipt(state, r#"{"commands":[{"command":"DateTime","parameters":"%Q"}]}"#).await;
+        assert!(matches!(printed, Err(AppError::Printer(PrinterError::Input(_)))));
+        assert_eq!(position(&dry_run.captured(), &DRAWER_KICK), None);
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn spooled_jobs_leave_the_drawer_shut() {
+        let (state, dry_run) = drawer_state();
+        print_without_drawer(state, r#"{"commands":[{"command":"Writeln","parameters":"TOTAL 1.00"}]}"#).await.unwrap();
+        let captured = dry_run.captured();
+        assert!(position(&captured, &CUT).is_some(), "the receipt is printed");
+        assert_eq!(position(&captured, &DRAWER_KICK), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn maintenance_suppresses_reconnects() {
+        // Every write to /dev/full fails, like a printer that has gone away.
+        let failing = PrinterDriver::File(FileDriver::open("/dev/full", false).unwrap());
+        let state = AppState::new(Some(failing), AppConfig::default());
+        state.set_maintenance(true);
+
+        let written = with_driver(&state, |d| async move { d.write(b"x") }).await;
+        assert!(written.is_err());
+        let polled = tokio::time::timeout(Duration::from_millis(50), poll_health(state.clone(), Duration::from_millis(1))).await;
+        assert!(polled.is_err(), "the poller keeps running");
+
+        assert!(state.metrics.render(false).contains("reika_reconnects_total 0\n"));
+        assert_eq!(state.status(), PrinterStatus::Offline);
+    }
+}
@@ -0,0 +1,62 @@
+use std::io::Cursor;
+
+use escpos::{errors::PrinterError, utils::JustifyMode};
+use image::{imageops, GrayImage, ImageFormat, Luma};
+
+/// Dots per Font A character, used to turn the configured line width into
+/// the print area: 32 characters is the 384 dots of 58mm paper.
+const DOTS_PER_CHAR: u32 = 12;
+
+pub fn paper_width_dots(paper_width_chars: usize) -> u32 {
+    paper_width_chars as u32 * DOTS_PER_CHAR
+}
+
+/// Reads just enough of the image at `path` to know its format and size, so
+/// a file that isn't a supported image is refused before the job starts.
+pub fn check_image(path: &str) -> Result<(), PrinterError> {
+    image::image_dimensions(path)
+        .map(|_| ())
+        .map_err(|e| PrinterError::Input(format!("image {:?} can't be decoded: {}", path, e)))
+}
+
+/// Loads the image at `path` and lays it out for the print head: scaled down
+/// to `max_width` keeping its aspect ratio, optionally dithered, then padded
+/// to the full `paper_dots` so the alignment doesn't depend on the printer
+/// honouring ESC a for rasters. Returns PNG bytes for escpos to rasterize.
+pub fn prepare_image(
+    path: &str,
+    align: JustifyMode,
+    max_width: u32,
+    dither: bool,
+    paper_dots: u32,
+) -> Result<Vec<u8>, PrinterError> {
+    let source = image::open(path).map_err(|e| PrinterError::Input(format!("image {:?} can't be decoded: {}", path, e)))?;
+    // A zero `paper_width_chars` would leave no room at all, and clamp panics
+    // on an empty range.
+    let paper_dots = paper_dots.max(1);
+    let max_width = max_width.clamp(1, paper_dots);
+    let source = if source.width() > max_width {
+        source.resize(max_width, u32::MAX, imageops::FilterType::Triangle)
+    } else {
+        source
+    };
+
+    let mut gray = source.to_luma8();
+    if dither {
+        imageops::dither(&mut gray, &imageops::BiLevel);
+    }
+
+    let x = match align {
+        JustifyMode::LEFT => 0,
+        JustifyMode::CENTER => (paper_dots - gray.width()) / 2,
+        JustifyMode::RIGHT => paper_dots - gray.width(),
+    };
+    let mut canvas = GrayImage::from_pixel(paper_dots, gray.height(), Luma([255]));
+    imageops::overlay(&mut canvas, &gray, x as i64, 0);
+
+    let mut bytes = Vec::new();
+    canvas
+        .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)
+        .map_err(|e| PrinterError::Io(e.to_string()))?;
+    Ok(bytes)
+}
@@ -1,134 +1,684 @@
-use std::convert::Infallible;
+use std::{convert::Infallible, sync::Arc, time::Duration};
 
-use escpos::{driver::UsbDriver, errors::PrinterError};
-use warp::{http::Method, http::StatusCode, Filter, reply::json};
+use escpos::errors::PrinterError;
+use tokio::sync::Notify;
+use tokio_stream::{wrappers::WatchStream, StreamExt};
+use warp::{http::Method, http::StatusCode, Filter, Reply, reply::json};
 
-use crate::{models::{parse_json, PrinterTestSchema, StatusResponse}, print::{handle_test_print, is_device_connected, print_receipt}};
+use crate::{config::ServerConfig, driver::{to_hex, DryRunDriver, PrinterDriver}, error::{AppError, MAINTENANCE_MESSAGE}, models::{check_datetime_formats, check_image_paths, BatchJobResult, BatchRequest, BatchResponse, execute_commands, parse_json, with_header_footer, validate_commands, CommandError, Commands, DeviceStatus, DryRunResponse, ErrorResponse, HealthResponse, MaintenanceMode, MaintenanceRequest, MaintenanceResponse, PrintedOn, PrintQuery, PrinterTestSchema, QueuedResponse, RawPrintResponse, StatusResponse, ValidationResponse, VersionResponse}, preview::render_preview, print::{check_connection, handle_test_print, keep_alive, poll_health, query_device_status, self_test, is_device_connected, open_cash_drawer, print_batch, print_raw, print_receipt}, idempotency::IdempotentOutcome, spool::{drain_spool, enqueue, has_queued_jobs}, state::AppState, usb::list_devices};
 
-pub async fn run( driver: UsbDriver) {
-    let routes = routes(driver);
+/// The test page served at `/`, embedded so the binary needs no assets.
+const UI_PAGE: &str = include_str!("ui.html");
+/// Where the test page itself is served from. Browsers send an Origin on its
+/// POSTs as well, so these pass CORS whatever `allowed_origins` lists.
+const UI_ORIGINS: [&str; 2] = ["http://127.0.0.1:55000", "http://localhost:55000"];
+/// Response header naming the printer a job came out of, `main` or `backup`.
+const PRINTED_ON_HEADER: &str = "X-Printed-On";
+/// How long Ctrl+C waits for a print in progress before exiting anyway.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub async fn run( state: AppState) {
+    if state.config.printer.spool_when_offline {
+        tokio::spawn(drain_spool(state.clone()));
+    }
+    let health_interval = state.config.printer.health_check_interval_secs;
+    if health_interval > 0 {
+        tokio::spawn(poll_health(state.clone(), Duration::from_secs(health_interval)));
+    }
+    let keepalive_interval = state.config.printer.keepalive_interval_secs;
+    if keepalive_interval > 0 {
+        tokio::spawn(keep_alive(state.clone(), Duration::from_secs(keepalive_interval)));
+    }
+    if state.config.printer.self_test_on_startup {
+        if state.is_online() {
+            tokio::spawn(self_test(state.clone()));
+        } else {
+            println!("Skipping the startup self-test, the printer is offline.");
+        }
+    }
+    let routes = routes(state.clone());
+    let shutdown = Arc::new(Notify::new());
+    let stop_accepting = shutdown.clone();
+    let (_, server) = warp::serve(routes)
+        .bind_with_graceful_shutdown(([127, 0, 0, 1], 55000), async move { stop_accepting.notified().await });
     println!("Serving the server!");
-    warp::serve(routes).run(([127, 0, 0, 1], 55000)).await;
-    
+    tokio::spawn(server);
+
+    // Exiting mid-write can leave the printer needing a power cycle, so let the current job finish first.
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        println!("Failed to listen for shutdown: {}", e);
+    }
+    println!("Shutting down, waiting for in-flight prints to finish...");
+    shutdown.notify_one();
+    if state.wait_idle(SHUTDOWN_TIMEOUT).await {
+        println!("No prints in flight, exiting.");
+    } else {
+        println!("Prints still running after {:?}, exiting anyway.", SHUTDOWN_TIMEOUT);
+    }
+}
+
+pub fn routes( state: AppState) -> impl Filter<Extract =  impl warp::Reply, Error = warp::Rejection> + Clone {
+    print_route(state.clone())
+        .or(receipt_route(state.clone()))
+        .or(raw_route(state.clone()))
+        .or(batch_route(state.clone()))
+        .or(drawer_route(state.clone()))
+        .or(devices_route())
+        .or(health_route(state.clone()))
+        .or(version_route(state.clone()))
+        .or(events_route(state.clone()))
+        .or(preview_route(state.clone()))
+        .or(validate_route(&state.config.server))
+        .or(maintenance_route(state.clone()))
+        .or(metrics_route(state.clone()))
+        .or(actions_route(state.clone()))
+        .or(ui_route())
+        .with(cors(&state.config.server))
+}
+
+fn with_state(
+    state: AppState,
+) -> impl Filter<Extract = (AppState,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || state.clone())
 }
 
-pub fn routes( driver: UsbDriver) -> impl Filter<Extract =  impl warp::Reply, Error = warp::Rejection> + Clone {
-    print_route(driver.clone()).or(receipt_route(driver))
+/// Refuses bodies over `max_body_bytes` with a 413 before warp buffers them.
+fn body_limit(config: &ServerConfig) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::body::content_length_limit(config.max_body_bytes)
 }
 
-fn with_driver(
-    driver: UsbDriver,
-) -> impl Filter<Extract = (UsbDriver,), Error = std::convert::Infallible> + Clone {
-    warp::any().map(move || driver.clone())
+/// Whether `origin` is a bare `scheme://host[:port]`, the only form warp takes.
+fn is_valid_origin(origin: &str) -> bool {
+    let (scheme, authority) = match origin.split_once("://") {
+        Some(parts) => parts,
+        None => return false,
+    };
+    let scheme_ok = scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+        && scheme.chars().all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c));
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if !authority.ends_with(']') => (host, Some(port)),
+        _ => (authority, None),
+    };
+    let host_ok = match host.strip_prefix('[').and_then(|host| host.strip_suffix(']')) {
+        Some(ipv6) => ipv6.parse::<std::net::Ipv6Addr>().is_ok(),
+        None => !host.is_empty() && host.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.'),
+    };
+    let port_ok = port.is_none_or(|port| port.chars().all(|c| c.is_ascii_digit()) && port.parse::<u16>().is_ok());
+    scheme_ok && host_ok && port_ok
 }
 
-fn cors() -> warp::cors::Cors {
-    warp::cors()
-        .allow_any_origin()
+fn cors(config: &ServerConfig) -> warp::cors::Cors {
+    let builder = if config.allowed_origins.is_empty() {
+        warp::cors().allow_any_origin()
+    } else {
+        // warp panics on an origin it can't parse, so skip the bad ones.
+        let origins: Vec<&str> = config
+            .allowed_origins
+            .iter()
+            .map(String::as_str)
+            .filter(|origin| {
+                let valid = is_valid_origin(origin);
+                if !valid {
+                    println!("Ignoring allowed origin {:?}, it must be scheme://host[:port] with no path", origin);
+                }
+                valid
+            })
+            .chain(UI_ORIGINS)
+            .collect();
+        warp::cors().allow_origins(origins)
+    };
+    builder
         .allow_methods(vec![Method::GET, Method::POST])
         .allow_headers(vec![
             "Content-Type",
             "Authorization",
             "Accept",
             "Origin",
+            "Idempotency-Key",
         ])
         .build()
 }
 
-pub fn print_route( driver: UsbDriver) -> impl Filter<Extract =  impl warp::Reply, Error = warp::Rejection> + Clone {
-    warp::path!("print" / "test").and(test(driver.clone()).or(status(driver))).with(cors())
+pub fn print_route( state: AppState) -> impl Filter<Extract =  impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("print" / "test").and(test(state.clone()).or(status(state)))
 }
 
 
-pub fn receipt_route(driver: UsbDriver) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    print(driver).with(cors())
+pub fn receipt_route(state: AppState) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    print(state)
 }
 
-fn print(driver: UsbDriver) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+/// Takes a complete ESC/POS stream as an `application/octet-stream` body for
+/// integrators that build their own bytes, and replies with the count sent.
+pub fn raw_route(state: AppState) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("print" / "raw")
+        .and(warp::post())
+        .and(body_limit(&state.config.server))
+        .and(with_state(state))
+        .and(warp::body::bytes())
+        .and_then(handle_raw)
+}
+
+/// Prints several jobs back to back, for report stacks that must not have
+/// another till's receipt land in the middle.
+pub fn batch_route(state: AppState) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("print" / "batch")
+        .and(warp::post())
+        .and(body_limit(&state.config.server))
+        .and(with_state(state))
+        .and(warp::body::json::<BatchRequest>())
+        .and_then(handle_batch)
+}
+
+pub fn drawer_route(state: AppState) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("drawer")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(with_state(state))
+        .and_then(handle_drawer)
+}
+
+pub fn preview_route(state: AppState) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("preview")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(body_limit(&state.config.server))
+        .and(with_state(state))
+        .and(warp::body::json::<Commands>())
+        .map(|state: AppState, commands: Commands| {
+            let commands = with_header_footer(commands, &state.config.printer);
+            render_preview(&commands, state.config.printer.paper_width_chars).join("\n")
+        })
+}
+
+/// A one-page tester for installers: send a print or check the status from
+/// any browser on the till.
+pub fn ui_route() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path::end()
+        .and(warp::get())
+        .map(|| warp::reply::html(UI_PAGE))
+}
+
+/// Liveness for load balancers. Reports the last known printer state and never
+/// probes USB, so polling it can't fire the cutter.
+pub fn health_route(state: AppState) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("health")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_state(state))
+        .map(|state: AppState| json(&HealthResponse {
+            status: "ok".to_string(),
+            printer_online: state.is_online(),
+            printer_status: state.status(),
+            offline_secs: state.offline_for().map(|elapsed| elapsed.as_secs()),
+            backup_status: state.backup_status(),
+        }))
+}
+
+/// Server-Sent Events feed of printer `status` events (`online`, `reconnecting`
+/// or `offline`).
+/// Each client gets the current state on connect, then every change.
+pub fn events_route(state: AppState) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("events")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_state(state))
+        .map(|state: AppState| {
+            let stream = WatchStream::new(state.subscribe_status()).map(|status| {
+                Ok::<_, Infallible>(warp::sse::Event::default()
+                    .event("status")
+                    .data(status.as_str()))
+            });
+            warp::sse::reply(warp::sse::keep_alive().stream(stream))
+        })
+}
+
+/// Prometheus scrape target. Like `/health`, it reads cached state only.
+pub fn metrics_route(state: AppState) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("metrics")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_state(state))
+        .map(|state: AppState| {
+            warp::reply::with_header(
+                state.metrics.render(state.is_online()),
+                "Content-Type",
+                "text/plain; version=0.0.4",
+            )
+        })
+}
+
+pub fn version_route(state: AppState) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("version")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_state(state))
+        .map(|state: AppState| json(&VersionResponse {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            preset: state.config.printer.preset.clone(),
+        }))
+}
+
+/// Dry validation of a print body. Reports every problem found, never prints.
+pub fn validate_route(config: &ServerConfig) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("validate")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(body_limit(config))
+        .and(warp::body::json::<serde_json::Value>())
+        .map(|body: serde_json::Value| {
+            let errors = match serde_json::from_value::<Commands>(body) {
+                Ok(commands) => validate_commands(&commands),
+                Err(e) => vec![CommandError { index: None, message: e.to_string() }],
+            };
+            json(&ValidationResponse { valid: errors.is_empty(), errors })
+        })
+}
+
+pub fn devices_route() -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("devices")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and_then(handle_devices)
+}
+
+/// `GET /actions` lists the configured quick actions by name, in order;
+/// `POST /actions/{index}` prints one.
+pub fn actions_route(state: AppState) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let list = warp::path!("actions")
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .map(|state: AppState| {
+            let names: Vec<&str> = state.config.printer.quick_actions.iter().map(|action| action.name.as_str()).collect();
+            json(&names).into_response()
+        });
+    let run = warp::path!("actions" / usize)
+        .and(warp::post())
+        .and(with_state(state))
+        .and_then(handle_action);
+    list.or(run)
+}
+
+async fn handle_action(index: usize, state: AppState) -> Result<warp::reply::Response, Infallible> {
+    if state.in_maintenance() {
+        return Ok(error_reply(&AppError::Maintenance));
+    }
+    let action = match state.config.printer.quick_actions.get(index) {
+        Some(action) => action,
+        None => return Ok(warp::reply::with_status("No such quick action.", StatusCode::NOT_FOUND).into_response()),
+    };
+    println!("Running quick action {:?}", action.name);
+    let json_commands = serde_json::to_string(&action.job).unwrap();
+    match print_middleman(state.clone(), &json_commands).await {
+        Ok(printed_on) => Ok(printed_reply(printed_on)),
+        Err(e) => Ok(error_reply(&e)),
+    }
+}
+
+pub fn maintenance_route(state: AppState) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path("maintenance")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(body_limit(&state.config.server))
+        .and(with_state(state))
+        .and(warp::body::json::<MaintenanceRequest>())
+        .and_then(handle_maintenance)
+}
+
+fn print(state: AppState) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path("print")
         .and(warp::path::end())
         .and(warp::post())
-        .and(with_driver(driver))
+        .and(body_limit(&state.config.server))
+        .and(with_state(state))
+        .and(warp::query::<PrintQuery>())
+        .and(warp::header::optional::<String>("idempotency-key"))
         .and(warp::body::json())
         .and_then(handle_request)
 }
 
 
-async fn handle_request(driver: UsbDriver, json_body: serde_json::Value) -> Result<impl warp::Reply, Infallible> {
-    let json_string = serde_json::to_string(&json_body).unwrap();
-    match print_middleman(driver, &json_string).await {
-        Ok(_) => Ok(warp::reply::with_status("Printed successfully", StatusCode::OK)),
+async fn handle_devices() -> Result<impl warp::Reply, Infallible> {
+    match list_devices() {
+        Ok(devices) => Ok(warp::reply::with_status(json(&devices), StatusCode::OK)),
         Err(e) => {
-            let response = match e {
-                PrinterError::Input(_) => {
-                    println!("Failed to parse the JSON for the previous print request!");
-                    warp::reply::with_status("Failed to parse the JSON.", StatusCode::BAD_REQUEST)
-                },
-                PrinterError::InvalidResponse(_) => {
-                    warp::reply::with_status("Failed to print: Invalid Response.", StatusCode::BAD_GATEWAY)
-                },
-                PrinterError::Io(_) => {
-                    warp::reply::with_status("Failed to print: IO Error", StatusCode::INTERNAL_SERVER_ERROR)
-                },
+            println!("Failed to list USB devices: {}", e);
+            Ok(warp::reply::with_status(
+                json(&serde_json::json!({ "error": e.to_string() })),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ))
+        }
+    }
+}
+
+async fn handle_maintenance(state: AppState, request: MaintenanceRequest) -> Result<impl warp::Reply, Infallible> {
+    let enabled = matches!(request.mode, MaintenanceMode::On);
+    state.set_maintenance(enabled);
+    if enabled {
+        println!("Maintenance mode turned on. Prints and reconnects are suspended.");
+    } else {
+        println!("Maintenance mode turned off.");
+    }
+    Ok(json(&MaintenanceResponse { maintenance: enabled }))
+}
+
+async fn handle_drawer(state: AppState) -> Result<warp::reply::Response, Infallible> {
+    if state.in_maintenance() {
+        return Ok(error_reply(&AppError::Maintenance));
+    }
+    let pin = state.config.printer.drawer_pin;
+    match open_cash_drawer(&state, pin).await {
+        Ok(_) => {
+            println!("Drawer open");
+            Ok(warp::reply::with_status("Drawer opened", StatusCode::OK).into_response())
+        },
+        Err(e) => {
+            println!("Failed to open the cash drawer: {}", e);
+            Ok(error_reply(&e))
+        }
+    }
+}
+
+async fn handle_raw(state: AppState, body: warp::hyper::body::Bytes) -> Result<warp::reply::Response, Infallible> {
+    if state.in_maintenance() {
+        println!("Rejected a raw print request, maintenance mode is on.");
+        return Ok(error_reply(&AppError::Maintenance));
+    }
+    if body.is_empty() {
+        return Ok(warp::reply::with_status("The body is empty.", StatusCode::BAD_REQUEST).into_response());
+    }
+    println!("Printing {} raw bytes", body.len());
+    match print_raw(&state, body.to_vec()).await {
+        Ok(bytes_written) => Ok(json(&RawPrintResponse { bytes_written }).into_response()),
+        Err(e) => {
+            println!("Raw print failed: {}", e);
+            Ok(error_reply(&e))
+        }
+    }
+}
+
+async fn handle_batch(state: AppState, batch: BatchRequest) -> Result<warp::reply::Response, Infallible> {
+    if state.in_maintenance() {
+        println!("Rejected a batch print request, maintenance mode is on.");
+        return Ok(error_reply(&AppError::Maintenance));
+    }
+    if batch.jobs.is_empty() {
+        return Ok(warp::reply::with_status("The batch has no jobs.", StatusCode::BAD_REQUEST).into_response());
+    }
+    // Checked up front so a bad job can't leave half the stack printed.
+    for (index, commands) in batch.jobs.iter().enumerate() {
+        if let Err(e) = preflight(&state, commands) {
+            println!("Rejected a batch, job {}: {}", index, e);
+            return Ok(error_reply(&e.into()));
+        }
+    }
+
+    println!("Printing a batch of {} jobs", batch.jobs.len());
+    let summaries: Vec<String> = batch.jobs.iter().map(Commands::summary).collect();
+    let outcomes = print_batch(&state, batch.jobs, batch.separator).await;
+    let mut status = StatusCode::OK;
+    let mut results = Vec::with_capacity(outcomes.len());
+    for (summary, outcome) in summaries.iter().zip(outcomes) {
+        match outcome {
+            Ok(_) => {
+                println!("Printed {}", summary);
+                results.push(BatchJobResult { printed: true, error: None });
+            }
+            Err(e) => {
+                println!("Failed to print {}: {}", summary, e);
+                if status == StatusCode::OK {
+                    status = e.status_code();
+                }
+                results.push(BatchJobResult { printed: false, error: Some(e.message()) });
+            }
+        }
+    }
+    Ok(warp::reply::with_status(json(&BatchResponse { results }), status).into_response())
+}
+
+fn error_reply(e: &AppError) -> warp::reply::Response {
+    let body = ErrorResponse {
+        error: e.message(),
+        code: e.code().to_string(),
+        retryable: e.retryable(),
+    };
+    warp::reply::with_status(json(&body), e.status_code()).into_response()
+}
+
+async fn handle_request(state: AppState, query: PrintQuery, idempotency_key: Option<String>, json_body: serde_json::Value) -> Result<warp::reply::Response, Infallible> {
+    if query.is_dry_run() {
+        let json_string = serde_json::to_string(&json_body).unwrap();
+        return Ok(dry_run_request(&state, &json_string).await);
+    }
+    // Claimed before anything else so a retry racing the first request
+    // can't slip in while it prints.
+    let claim = match state.claim_idempotency_key(idempotency_key) {
+        Ok(claim) => claim,
+        Err(outcome) => {
+            println!("Idempotency key seen before, not printing again.");
+            return Ok(outcome_reply(outcome));
+        }
+    };
+    match print_request(&state, &json_body).await {
+        Ok(outcome) => {
+            claim.finish(outcome.clone());
+            Ok(outcome_reply(outcome))
+        },
+        Err(response) => Ok(response),
+    }
+}
+
+/// Prints or spools the body, giving what to remember for its key.
+async fn print_request(state: &AppState, json_body: &serde_json::Value) -> Result<IdempotentOutcome, warp::reply::Response> {
+    if state.in_maintenance() {
+        println!("Rejected a print request, maintenance mode is on.");
+        return Err(error_reply(&AppError::Maintenance));
+    }
+    let json_string = serde_json::to_string(json_body).unwrap();
+    // While older jobs are still spooled, a new one queues behind them instead
+    // of printing ahead of them the moment the printer is back.
+    if state.config.printer.spool_when_offline && (has_queued_jobs() || !check_connection(&state.config.printer)) {
+        return spool_request(state, &json_string).map(IdempotentOutcome::Queued);
+    }
+    match print_middleman(state.clone(), &json_string).await {
+        Ok(printed_on) => Ok(IdempotentOutcome::Printed(printed_on)),
+        Err(e) => {
+            if let AppError::Printer(PrinterError::Input(_)) = e {
+                println!("Failed to parse the JSON for the previous print request!");
+            }
+            Err(error_reply(&e))
+        }
+    }
+}
+
+/// Runs the job against a capturing driver and returns the bytes as hex.
+/// Nothing reaches the printer, so maintenance and the spool don't apply.
+async fn dry_run_request(state: &AppState, json_commands: &str) -> warp::reply::Response {
+    let commands = match parse_json(json_commands) {
+        Ok(commands) => commands,
+        Err(_) => {
+            println!("Failed to parse the JSON for the previous print request!");
+            return warp::reply::with_status("Failed to parse the JSON.", StatusCode::BAD_REQUEST).into_response();
+        }
+    };
+    let dry_run = DryRunDriver::default();
+    match execute_commands(PrinterDriver::DryRun(dry_run.clone()), commands, &state.config.printer).await {
+        Ok(_) => {
+            let captured = dry_run.captured();
+            json(&DryRunResponse { length: captured.len(), bytes: to_hex(&captured) }).into_response()
+        },
+        Err(e) => {
+            println!("Dry run failed: {}", e);
+            warp::reply::with_status("Failed to encode the commands.", StatusCode::BAD_REQUEST).into_response()
+        }
+    }
+}
+
+/// The usual plain-text success body. Which printer served the job rides in
+/// a header, so clients reading the body see no change.
+fn printed_reply(printed_on: PrintedOn) -> warp::reply::Response {
+    let reply = warp::reply::with_status("Printed successfully", StatusCode::OK);
+    warp::reply::with_header(reply, PRINTED_ON_HEADER, printed_on.as_str()).into_response()
+}
+
+fn outcome_reply(outcome: IdempotentOutcome) -> warp::reply::Response {
+    match outcome {
+        IdempotentOutcome::InProgress => {
+            let body = ErrorResponse {
+                error: "A request with this Idempotency-Key is still printing.".to_string(),
+                code: "IN_PROGRESS".to_string(),
+                retryable: true,
             };
-            Ok(response)
+            warp::reply::with_status(json(&body), StatusCode::CONFLICT).into_response()
+        },
+        IdempotentOutcome::Printed(printed_on) => printed_reply(printed_on),
+        IdempotentOutcome::Queued(job_id) => {
+            warp::reply::with_status(json(&QueuedResponse { job_id }), StatusCode::ACCEPTED).into_response()
+        },
+    }
+}
+
+/// Stores the job in the offline spool and returns its id, or the error reply.
+fn spool_request(state: &AppState, json_commands: &str) -> Result<String, warp::reply::Response> {
+    let commands = match parse_json(json_commands) {
+        Ok(commands) => commands,
+        Err(_) => {
+            println!("Failed to parse the JSON for the previous print request!");
+            return Err(warp::reply::with_status("Failed to parse the JSON.", StatusCode::BAD_REQUEST).into_response());
+        }
+    };
+    if let Err(e) = preflight(state, &commands) {
+        println!("Rejected a print request: {}", e);
+        return Err(error_reply(&e.into()));
+    }
+    match enqueue(json_commands, state.config.printer.spool_max_jobs) {
+        Ok(job_id) => {
+            println!("Printer offline, spooled job {}", job_id);
+            Ok(job_id)
+        },
+        Err(e) => {
+            println!("Printer offline and the job could not be spooled: {}", e);
+            Err(warp::reply::with_status("Printer offline and the job could not be queued.", StatusCode::SERVICE_UNAVAILABLE).into_response())
+        }
+    }
+}
+
+/// The checks a job passes before it is printed or spooled, so one that can't
+/// print is refused while the client is still there to hear about it.
+fn preflight(state: &AppState, commands: &Commands) -> Result<(), PrinterError> {
+    if state.config.printer.validate_before_print {
+        let errors = validate_commands(commands);
+        if !errors.is_empty() {
+            return Err(PrinterError::Input(format!("{} invalid command(s): {:?}", errors.len(), errors)));
         }
     }
+    check_image_paths(commands, &state.config.printer.image_dir)?;
+    check_datetime_formats(commands)
 }
 
-async fn print_middleman(driver: UsbDriver, json_commands: &str) -> Result<(), PrinterError> {
+async fn print_middleman(state: AppState, json_commands: &str) -> Result<PrintedOn, AppError> {
     println!("print_middleman");
     match parse_json(json_commands) {
-        Ok(_) => {
+        Ok(commands) => {
+            let summary = commands.summary();
+            println!("Received {}", summary);
+            if let Err(e) = preflight(&state, &commands) {
+                println!("Rejected a print request: {}", e);
+                return Err(e.into());
+            }
             // Continue execution if parsing was successful
-            print_receipt(driver, json_commands).await.map_err(|e| {
-                // Map your specific error here based on the context of the error
-                match e {
-                    PrinterError::Input(error) => PrinterError::Input(error),
-                    PrinterError::InvalidResponse(error) => PrinterError::InvalidResponse(error),
-                    PrinterError::Io(error) => PrinterError::Io(error),
-                }
-            })
+            let printed = print_receipt(state, json_commands).await;
+            match &printed {
+                Ok(PrintedOn::Main) => println!("Printed {}", summary),
+                Ok(PrintedOn::Backup) => println!("Printed {} on the backup printer", summary),
+                Err(e) => println!("Failed to print {}: {}", summary, e),
+            }
+            printed
         },
         Err(e) => {
             // Return the parsing error
-            Err(e)
+            Err(e.into())
         }
     }
 }
 
 
-fn test(driver: UsbDriver) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+fn test(state: AppState) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path::end()
         .and(warp::post())
-        .and(with_driver(driver.clone()))
+        .and(body_limit(&state.config.server))
+        .and(with_state(state.clone()))
         .and(warp::body::json::<PrinterTestSchema>())
-        .and_then(|driver: UsbDriver, print_request:PrinterTestSchema| async move {
-            match handle_test_print(driver, print_request).await {
-                Ok(_) => Ok::<_, warp::Rejection>(warp::reply::with_status("Printed successfully", StatusCode::OK)),
+        .and_then(|state: AppState, print_request:PrinterTestSchema| async move {
+            if state.in_maintenance() {
+                return Ok::<_, warp::Rejection>(error_reply(&AppError::Maintenance));
+            }
+            match handle_test_print(state, print_request).await {
+                Ok(_) => Ok::<_, warp::Rejection>(warp::reply::with_status("Printed successfully", StatusCode::OK).into_response()),
                 Err(_) => Err(warp::reject::reject()),
             }
         })
 }
 
-fn status(driver: UsbDriver) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+fn status(state: AppState) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     warp::path::end()
         .and(warp::get())
-        .and(with_driver(driver.clone()))
-        .and_then(|driver: UsbDriver| async move {
-            status_handler(driver).await
+        .and(with_state(state.clone()))
+        .and_then(|state: AppState| async move {
+            status_handler(state).await
         })
         .boxed()
 }
 
-async fn status_handler(driver: UsbDriver) -> Result<impl warp::Reply, warp::Rejection> {
-    let is_connected = is_device_connected(driver).await;
+async fn status_handler(state: AppState) -> Result<impl warp::Reply, warp::Rejection> {
+    if state.in_maintenance() {
+        return Ok(warp::reply::with_status(
+            json(&StatusResponse {
+                is_connected: false,
+                error: MAINTENANCE_MESSAGE.to_string(),
+                paper_out: false,
+                paper_low: false,
+                cover_open: false,
+            }),
+            StatusCode::OK,
+        ));
+    }
+    // With the health poller running, answer from what it last saw so a
+    // status poll never waits on the printer lock behind a print.
+    let (is_connected, device) = if state.config.printer.health_check_interval_secs > 0 {
+        (state.is_online(), state.device_status())
+    } else {
+        let is_connected = is_device_connected(state.clone()).await;
+        // A print holding the printer keeps it; the last reading stands in.
+        let device = if !is_connected {
+            DeviceStatus::default()
+        } else if let Some(_printing) = state.try_lock_printer() {
+            let device = query_device_status(&state).await;
+            state.set_device_status(device);
+            device
+        } else {
+            state.device_status()
+        };
+        (is_connected, device)
+    };
     if is_connected {
         println!("Connected sent!");
+        let error = if device.paper_out {
+            "The printer is out of paper."
+        } else if device.cover_open {
+            "The printer cover is open."
+        } else if device.paper_low {
+            "The printer is almost out of paper."
+        } else {
+            "Printer is connected"
+        };
         Ok(warp::reply::with_status(
             json(&StatusResponse {
                 is_connected,
-                error: "Printer is connected".to_string(),
+                error: error.to_string(),
+                paper_out: device.paper_out,
+                paper_low: device.paper_low,
+                cover_open: device.cover_open,
             }),
             StatusCode::OK,
         ))
@@ -138,8 +688,55 @@ async fn status_handler(driver: UsbDriver) -> Result<impl warp::Reply, warp::Rej
             json(&StatusResponse {
                 is_connected,
                 error: "The thermal printer is either not plugged in, or is in a not ready state.".to_string(),
+                paper_out: false,
+                paper_low: false,
+                cover_open: false,
             }),
             StatusCode::OK,
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AppConfig;
+
+    #[tokio::test]
+    async fn print_is_rejected_during_maintenance() {
+        let state = AppState::new(None, AppConfig::default());
+        state.set_maintenance(true);
+        let response = warp::test::request()
+            .method("POST")
+            .path("/print")
+            .json(&serde_json::json!({ "commands": [{ "command": "Writeln", "parameters": "hello" }] }))
+            .reply(&routes(state))
+            .await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body: ErrorResponse = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body.code, "MAINTENANCE");
+    }
+
+    #[tokio::test]
+    async fn invalid_gs1_data_is_explained() {
+        let state = AppState::new(Some(PrinterDriver::DryRun(DryRunDriver::default())), AppConfig::default());
+        let response = warp::test::request()
+            .method("POST")
+            .path("/print")
+            .json(&serde_json::json!({ "commands": [{ "command": "Gs1128", "parameters": "(01)123" }] }))
+            .reply(&routes(state))
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body: ErrorResponse = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body.code, "INVALID_INPUT");
+        assert!(body.error.contains("GS1-128 AI (01) takes exactly 14 characters"), "{}", body.error);
+    }
+
+    #[test]
+    fn ui_sample_body_parses() {
+        let (_, after_open) = UI_PAGE.split_once(r#"<textarea id="body">"#).unwrap();
+        let (sample, _) = after_open.split_once("</textarea>").unwrap();
+        let commands = parse_json(sample).unwrap();
+        assert_eq!(commands.commands.len(), 4);
+    }
+}
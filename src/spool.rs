@@ -0,0 +1,120 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use escpos::errors::PrinterError;
+use tokio::time::sleep;
+
+use crate::{config::data_dir, error::AppError, print::{check_connection, print_without_drawer}, state::AppState};
+
+const SPOOL_DIR: &str = "spool";
+/// Inside the spool, for jobs that failed as invalid input and so never will print.
+const REJECTED_DIR: &str = "rejected";
+const DRAIN_INTERVAL: Duration = Duration::from_secs(5);
+
+static SEQUENCE: AtomicU32 = AtomicU32::new(0);
+
+fn spool_dir() -> PathBuf {
+    data_dir().join(SPOOL_DIR)
+}
+
+/// Queued job files, oldest first. Ids start with the enqueue time, so sorting
+/// by file name is FIFO order.
+fn queued_jobs() -> io::Result<Vec<PathBuf>> {
+    let dir = spool_dir();
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut jobs: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    jobs.sort();
+    Ok(jobs)
+}
+
+/// Moves `job` out of the queue into `rejected/`, where it can still be looked at.
+fn reject(job: &Path) -> io::Result<PathBuf> {
+    let dir = spool_dir().join(REJECTED_DIR);
+    fs::create_dir_all(&dir)?;
+    let target = dir.join(job.file_name().unwrap_or_default());
+    fs::rename(job, &target)?;
+    Ok(target)
+}
+
+/// Whether any job is still waiting in the spool. New jobs queue up behind
+/// those rather than overtaking them.
+pub fn has_queued_jobs() -> bool {
+    queued_jobs().is_ok_and(|jobs| !jobs.is_empty())
+}
+
+/// Writes an already-validated print body to the spool and returns its job id.
+pub fn enqueue(json_commands: &str, max_jobs: usize) -> io::Result<String> {
+    if queued_jobs()?.len() >= max_jobs {
+        return Err(io::Error::other("the print spool is full"));
+    }
+    let dir = spool_dir();
+    fs::create_dir_all(&dir)?;
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis())
+        .unwrap_or(0);
+    let sequence = SEQUENCE.fetch_add(1, Ordering::SeqCst) % 10_000;
+    let id = format!("{:013}-{:04}", millis, sequence);
+    fs::write(dir.join(format!("{}.json", id)), json_commands)?;
+    Ok(id)
+}
+
+/// Background task that prints spooled jobs in order once the printer is back.
+/// It is the only thing that prints them, so it sets the order; the drawer
+/// isn't kicked for them.
+/// A failing job stays queued and blocks the ones behind it, keeping order,
+/// unless it failed as invalid input: that would block the queue for good, so
+/// it is moved to `rejected/` instead.
+pub async fn drain_spool(state: AppState) {
+    loop {
+        sleep(DRAIN_INTERVAL).await;
+        if state.in_maintenance() || !check_connection(&state.config.printer) {
+            continue;
+        }
+        let jobs = match queued_jobs() {
+            Ok(jobs) => jobs,
+            Err(e) => {
+                println!("Failed to read the print spool: {}", e);
+                continue;
+            }
+        };
+        for job in jobs {
+            let json_commands = match fs::read_to_string(&job) {
+                Ok(contents) => contents,
+                Err(e) => {
+                    println!("Failed to read spooled job {}: {}", job.display(), e);
+                    break;
+                }
+            };
+            println!("Printing spooled job {}", job.display());
+            match print_without_drawer(state.clone(), &json_commands).await {
+                Ok(_) => {
+                    if let Err(e) = fs::remove_file(&job) {
+                        println!("Printed spooled job {} but failed to remove it: {}", job.display(), e);
+                    }
+                }
+                Err(AppError::Printer(PrinterError::Input(e))) => match reject(&job) {
+                    Ok(target) => println!("Spooled job {} is invalid ({}), moved it to {}", job.display(), e, target.display()),
+                    Err(move_error) => {
+                        println!("Spooled job {} is invalid ({}) and could not be moved aside: {}", job.display(), e, move_error);
+                        break;
+                    }
+                },
+                Err(e) => {
+                    println!("Spooled job {} failed: {}", job.display(), e);
+                    break;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,180 @@
+use std::sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::{watch, Mutex as AsyncMutex, MutexGuard as AsyncMutexGuard};
+
+use crate::config::AppConfig;
+use crate::driver::PrinterDriver;
+use crate::idempotency::{IdempotencyCache, IdempotencyClaim, IdempotentOutcome};
+use crate::metrics::Metrics;
+use crate::models::{DeviceStatus, PrinterStatus};
+use crate::print::check_connection;
+
+/// Everything the routes and the print paths share. Cloning is cheap and every
+/// clone points at the same underlying state.
+#[derive(Clone)]
+pub struct AppState {
+    driver: Arc<Mutex<Option<PrinterDriver>>>,
+    pub config: Arc<AppConfig>,
+    pub metrics: Arc<Metrics>,
+    maintenance: Arc<AtomicBool>,
+    status_tx: Arc<watch::Sender<PrinterStatus>>,
+    idempotency: Arc<Mutex<IdempotencyCache>>,
+    jobs_tx: Arc<watch::Sender<usize>>,
+    print_lock: Arc<AsyncMutex<()>>,
+    device_status: Arc<Mutex<DeviceStatus>>,
+    offline_since: Arc<Mutex<Option<Instant>>>,
+    backup_status: Arc<Mutex<Option<PrinterStatus>>>,
+}
+
+/// Marks a job as in flight until dropped.
+pub struct JobGuard {
+    jobs_tx: Arc<watch::Sender<usize>>,
+}
+
+impl Drop for JobGuard {
+    fn drop(&mut self) {
+        self.jobs_tx.send_modify(|jobs| *jobs = jobs.saturating_sub(1));
+    }
+}
+
+impl AppState {
+    /// `driver` is `None` when the printer couldn't be opened at startup; the
+    /// service then starts offline and picks it up on reconnect.
+    pub fn new(driver: Option<PrinterDriver>, config: AppConfig) -> AppState {
+        let offline_since = if driver.is_some() { None } else { Some(Instant::now()) };
+        let backup_status = config.printer.backup_config().map(|backup| {
+            if check_connection(&backup) { PrinterStatus::Online } else { PrinterStatus::Offline }
+        });
+        AppState {
+            status_tx: Arc::new(watch::channel(if driver.is_some() { PrinterStatus::Online } else { PrinterStatus::Offline }).0),
+            driver: Arc::new(Mutex::new(driver)),
+            config: Arc::new(config),
+            metrics: Arc::new(Metrics::default()),
+            maintenance: Arc::new(AtomicBool::new(false)),
+            idempotency: Arc::new(Mutex::new(IdempotencyCache::default())),
+            jobs_tx: Arc::new(watch::channel(0).0),
+            print_lock: Arc::new(AsyncMutex::new(())),
+            device_status: Arc::new(Mutex::new(DeviceStatus::default())),
+            offline_since: Arc::new(Mutex::new(offline_since)),
+            backup_status: Arc::new(Mutex::new(backup_status)),
+        }
+    }
+
+    /// The current connection, shared by every clone so a reconnect made by
+    /// one job is seen by the next.
+    pub fn driver(&self) -> Option<PrinterDriver> {
+        self.driver.lock().unwrap().clone()
+    }
+
+    pub fn set_driver(&self, driver: PrinterDriver) {
+        *self.driver.lock().unwrap() = Some(driver);
+    }
+
+    pub fn begin_job(&self) -> JobGuard {
+        self.jobs_tx.send_modify(|jobs| *jobs += 1);
+        JobGuard { jobs_tx: self.jobs_tx.clone() }
+    }
+
+    /// Held for the whole of a job so jobs reach the printer one at a time and
+    /// their bytes never interleave, whichever route they came in on.
+    pub async fn lock_printer(&self) -> AsyncMutexGuard<'_, ()> {
+        self.print_lock.lock().await
+    }
+
+    /// The lock, if no job holds it right now. For background probes that
+    /// should give way to prints rather than queue behind them.
+    pub fn try_lock_printer(&self) -> Option<AsyncMutexGuard<'_, ()>> {
+        self.print_lock.try_lock().ok()
+    }
+
+    /// Waits until no job is talking to the printer. Returns `false` if some
+    /// were still running when `timeout` ran out.
+    pub async fn wait_idle(&self, timeout: Duration) -> bool {
+        let mut jobs_rx = self.jobs_tx.subscribe();
+        tokio::time::timeout(timeout, jobs_rx.wait_for(|jobs| *jobs == 0))
+            .await
+            .is_ok()
+    }
+
+    /// Last known printer state, as seen by the most recent print or probe.
+    /// Reading it never touches USB.
+    pub fn status(&self) -> PrinterStatus {
+        *self.status_tx.borrow()
+    }
+
+    pub fn is_online(&self) -> bool {
+        self.status() == PrinterStatus::Online
+    }
+
+    /// Paper and cover sensors as of the last health poll.
+    pub fn device_status(&self) -> DeviceStatus {
+        *self.device_status.lock().unwrap()
+    }
+
+    pub fn set_device_status(&self, status: DeviceStatus) {
+        *self.device_status.lock().unwrap() = status;
+    }
+
+    /// The backup printer as of the last health poll or fallback print,
+    /// `None` when none is configured.
+    pub fn backup_status(&self) -> Option<PrinterStatus> {
+        *self.backup_status.lock().unwrap()
+    }
+
+    pub fn set_backup_status(&self, status: PrinterStatus) {
+        let mut current = self.backup_status.lock().unwrap();
+        if *current != Some(status) {
+            println!("Backup printer is now {}.", status.as_str());
+        }
+        *current = Some(status);
+    }
+
+    /// Claims an `Idempotency-Key` for this request, or returns the outcome
+    /// already recorded for it within its TTL. Without a key every request
+    /// gets a claim that records nothing.
+    pub fn claim_idempotency_key(&self, key: Option<String>) -> Result<IdempotencyClaim, IdempotentOutcome> {
+        if let Some(key) = &key {
+            if let Some(outcome) = self.idempotency.lock().unwrap().claim(key) {
+                return Err(outcome);
+            }
+        }
+        Ok(IdempotencyClaim::new(self.idempotency.clone(), key))
+    }
+
+    pub fn subscribe_status(&self) -> watch::Receiver<PrinterStatus> {
+        self.status_tx.subscribe()
+    }
+
+    pub fn update_status(&self, status: PrinterStatus) {
+        self.status_tx.send_if_modified(|current| {
+            if *current == status {
+                return false;
+            }
+            println!("Printer is now {}.", status.as_str());
+            let mut offline_since = self.offline_since.lock().unwrap();
+            if status == PrinterStatus::Online {
+                *offline_since = None;
+            } else if offline_since.is_none() {
+                *offline_since = Some(Instant::now());
+            }
+            *current = status;
+            true
+        });
+    }
+
+    /// How long the printer has been anything but online, `None` while it is.
+    pub fn offline_for(&self) -> Option<Duration> {
+        self.offline_since.lock().unwrap().map(|since| since.elapsed())
+    }
+
+    /// While maintenance is on, prints are rejected and nothing tries to
+    /// reconnect to the printer, so a tech can replug or clear a jam in peace.
+    pub fn in_maintenance(&self) -> bool {
+        self.maintenance.load(Ordering::SeqCst)
+    }
+
+    pub fn set_maintenance(&self, enabled: bool) {
+        self.maintenance.store(enabled, Ordering::SeqCst);
+    }
+}
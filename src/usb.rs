@@ -0,0 +1,84 @@
+use rusb::{Direction, TransferType};
+
+use crate::config::{NamedPreset, KNOWN_PRINTERS};
+use crate::models::{UsbDeviceInfo, UsbEndpointInfo};
+
+/// Whether a device with these ids is on the bus. This only enumerates, it
+/// never opens the printer or sends it anything.
+pub fn is_present(vendor_id: u16, product_id: u16) -> bool {
+    match rusb::devices() {
+        Ok(devices) => devices.iter().any(|device| {
+            device
+                .device_descriptor()
+                .map(|descriptor| descriptor.vendor_id() == vendor_id && descriptor.product_id() == product_id)
+                .unwrap_or(false)
+        }),
+        Err(_) => false,
+    }
+}
+
+/// Every USB device on the bus with its bulk endpoints, for finding a
+/// printer's VID/PID during setup. The manufacturer and product strings are
+/// `None` when the device can't be opened, which is common without drivers.
+pub fn list_devices() -> Result<Vec<UsbDeviceInfo>, rusb::Error> {
+    let mut found = Vec::new();
+    for device in rusb::devices()?.iter() {
+        let descriptor = match device.device_descriptor() {
+            Ok(descriptor) => descriptor,
+            Err(_) => continue,
+        };
+
+        let (manufacturer, product) = match device.open() {
+            Ok(handle) => (
+                handle.read_manufacturer_string_ascii(&descriptor).ok(),
+                handle.read_product_string_ascii(&descriptor).ok(),
+            ),
+            Err(_) => (None, None),
+        };
+
+        let mut bulk_endpoints = Vec::new();
+        if let Ok(config) = device.active_config_descriptor() {
+            for interface in config.interfaces() {
+                for interface_descriptor in interface.descriptors() {
+                    for endpoint in interface_descriptor.endpoint_descriptors() {
+                        if endpoint.transfer_type() != TransferType::Bulk {
+                            continue;
+                        }
+                        bulk_endpoints.push(UsbEndpointInfo {
+                            interface: interface_descriptor.interface_number(),
+                            address: endpoint.address(),
+                            direction: match endpoint.direction() {
+                                Direction::In => "in".to_string(),
+                                Direction::Out => "out".to_string(),
+                            },
+                        });
+                    }
+                }
+            }
+        }
+
+        found.push(UsbDeviceInfo {
+            vendor_id: descriptor.vendor_id(),
+            product_id: descriptor.product_id(),
+            manufacturer,
+            product,
+            bulk_endpoints,
+        });
+    }
+    Ok(found)
+}
+
+/// The first device on the bus that is in `KNOWN_PRINTERS`, as a preset.
+pub fn detect_known_printer() -> Option<NamedPreset> {
+    let devices = list_devices().ok()?;
+    devices.iter().find_map(|device| {
+        KNOWN_PRINTERS
+            .iter()
+            .find(|(_, vendor_id, product_id)| device.vendor_id == *vendor_id && device.product_id == *product_id)
+            .map(|(name, vendor_id, product_id)| NamedPreset {
+                name: name.to_string(),
+                vendor_id: *vendor_id,
+                product_id: *product_id,
+            })
+    })
+}